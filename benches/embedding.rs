@@ -0,0 +1,86 @@
+// Compares embedding/extraction on a 4K cover with a single rayon thread (the
+// old serial behaviour) against the full pool. Run with `cargo bench`.
+#![allow(dead_code, unused_imports)]
+
+#[path = "../src/commons.rs"]
+mod commons;
+#[path = "../src/encryption.rs"]
+mod encryption;
+#[path = "../src/fragment.rs"]
+mod fragment;
+
+use encryption::{embed, extract, payload_capacity, PixelOrder, PART_HEADER_LEN};
+use image::{ImageBuffer, Rgba};
+use std::time::{Duration, Instant};
+
+const WIDTH: u32 = 3840;
+const HEIGHT: u32 = 2160;
+const ROUNDS: u32 = 5;
+
+fn time(pool: &rayon::ThreadPool, mut f: impl FnMut() + Send) -> Duration {
+    pool.install(|| {
+        f(); // warm up
+        let start = Instant::now();
+        for _ in 0..ROUNDS {
+            f();
+        }
+        start.elapsed() / ROUNDS
+    })
+}
+
+fn main() {
+    let cover: ImageBuffer<Rgba<u8>, Vec<u8>> = ImageBuffer::from_fn(WIDTH, HEIGHT, |x, y| {
+        Rgba([x as u8, y as u8, (x ^ y) as u8, 255])
+    });
+    let secret: Vec<u8> = (0..(payload_capacity(WIDTH, HEIGHT) - PART_HEADER_LEN) as u32)
+        .map(|i| (i % 251) as u8)
+        .collect();
+    let header = [0, 0, 0, 0, 0, 0, 0, 1]
+        .into_iter()
+        .chain((secret.len() as u32).to_be_bytes());
+    let stream: Vec<u8> = header.chain(secret.iter().copied()).collect();
+
+    let serial = rayon::ThreadPoolBuilder::new()
+        .num_threads(1)
+        .build()
+        .unwrap();
+    let parallel = rayon::ThreadPoolBuilder::new().build().unwrap();
+    println!(
+        "{}x{} cover, {} bytes, {} threads",
+        cover.width(),
+        cover.height(),
+        secret.len(),
+        parallel.current_num_threads()
+    );
+
+    for (name, order) in [
+        ("raster", PixelOrder::Raster),
+        ("keyed", PixelOrder::Keyed([7; 32])),
+    ] {
+        let mut encoded = cover.clone();
+        let embed_times: Vec<Duration> = [&serial, &parallel]
+            .iter()
+            .map(|pool| time(pool, || embed(&mut encoded, &stream, order)))
+            .collect();
+        let extract_times: Vec<Duration> = [&serial, &parallel]
+            .iter()
+            .map(|pool| {
+                time(pool, || {
+                    let (_, out) = extract(&encoded, order).unwrap();
+                    assert_eq!(out.len(), secret.len());
+                })
+            })
+            .collect();
+
+        for (op, times) in [("embed", embed_times), ("extract", extract_times)] {
+            println!(
+                "{:<7} {:<7} serial {:>8.1?}  parallel {:>8.1?}  speed-up {:.2}x",
+                name,
+                op,
+                times[0],
+                times[1],
+                times[0].as_secs_f64() / times[1].as_secs_f64()
+            );
+        }
+    }
+}
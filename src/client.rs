@@ -3,6 +3,7 @@
     unused_variables,
     unused_imports,
     clippy::redundant_allocation,
+    clippy::too_many_arguments,
     unused_assignments
 )]
 
@@ -10,44 +11,98 @@ extern crate serde;
 extern crate serde_derive;
 extern crate serde_json;
 use crate::commons::{
-    self, Action, ENCRYPTED_PICS_PATH, HIGH_RES_PICS_PATH, LOW_RES_PICS_PATH, PICS_ROOT_PATH,
-    REQ_ID_LOG_FILEPATH,
+    self, Action, CountedGrant, OnlineGrant, ShareTerms, Signed, EMBEDDING_KEY_FILEPATH,
+    ENCRYPTED_PICS_PATH, HIGH_RES_PICS_PATH, LOW_RES_PICS_PATH, PICS_ROOT_PATH,
 };
 use crate::dir_of_service::ClientDirOfService;
-use crate::encryption::{decode_img, encode_img};
+use crate::encryption::{decode_parts, encode_img, rewrite_parts, PixelOrder};
 use crate::fragment::{self, BigMessage};
+use crate::identity::{self, Identity, Keyring};
+use crate::manifest::{
+    AccessDenied, AccessManifest, ManifestRecord, Permissions, SharePolicy, SignedManifest,
+};
+use crate::store::{RequestIds, StateStore};
 use crate::utils::{
-    create_output_dirs, file_exists, get_cloud_servers, get_pic_paths, get_req_id_log, mkdir,
+    create_output_dirs, file_exists, format_time, get_cloud_servers, get_pic_paths,
+    get_pixel_order, mkdir, now_secs, parse_duration, secure_delete,
 };
+use crate::watermark::{self, Watermark, WatermarkMode};
 use commons::{Msg, Type};
-use commons::{BUFFER_SIZE, ELECTION_PORT, SERVERS_FILEPATH, SERVICE_PORT, SERVICE_SENDBACK_PORT};
+use commons::{
+    BUFFER_SIZE, ELECTION_PORT, SERVERS_FILEPATH, SERVICE_PORT, SERVICE_SENDBACK_PORT,
+    TIMEOUT_MILLIS,
+};
 use fragment::Image;
 use image::{open, ImageBuffer, Rgba};
 use log::{error, info, log, trace, warn};
 use minifb::{Key, Window, WindowOptions};
-use std::collections::HashMap;
+use rand::Rng;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
 use std::net::SocketAddr;
 use std::path::Path;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::{env, fs as std_fs};
 use tokio::io::AsyncBufReadExt;
 use tokio::net::UdpSocket;
-use tokio::sync::Mutex;
-use tokio::time::sleep;
+use tokio::sync::{oneshot, Mutex};
+use tokio::time::{sleep, timeout};
 use tokio::{self, fs};
 
+// view token requests waiting for the owner's answer, by nonce
+type ViewTokens = Arc<Mutex<HashMap<u64, oneshot::Sender<Option<CountedGrant>>>>>;
+
+// the last directory of service we got, and when, so receipts do not query it again
+type DirCache = Mutex<Option<(Instant, HashMap<SocketAddr, bool>)>>;
+const DIR_CACHE_SECS: u64 = 60;
+
+// One of our images as shared with one recipient (img_id is owner&recipient&name)
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct OwnShare {
+    pub img_id: String,
+    pub remaining: u32,
+    pub last_viewed: Option<u64>, // unix time (secs), from the recipient's view receipts
+    pub status: ShareStatus,
+    #[serde(default)]
+    pub serial: u64, // of the terms last signed for this share
+    #[serde(default)]
+    pub expiry: Option<u64>, // unix time (secs), None never expires
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum ShareStatus {
+    Active,
+    Revoked,          // revoked, waiting for the recipient to delete its copy
+    RevokedConfirmed, // the recipient deleted its copy
+}
+
+// Something a peer asked us for, waiting for our approval in View Requests
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum Request {
+    Access(Action),       // an access update on an image they already have
+    Image(u32, [u8; 32]), // the image itself: requested views, their identity key
+}
+
 pub struct ClientBackend {
     cloud_socket: Arc<UdpSocket>,
     pub client_socket: Arc<UdpSocket>,
-    next_req_id: u32,
+    req_ids: RequestIds,
     mode: String,
-    cloud_servers: Vec<(SocketAddr, SocketAddr)>,
+    cloud_servers: Arc<Mutex<Vec<(SocketAddr, SocketAddr)>>>, // refreshed from the servers' replies
     dir_of_serv: ClientDirOfService,
+    dir_cache: DirCache,
+    pixel_order: PixelOrder,
+    identity: Arc<Identity>,
+    keyring: Arc<Mutex<Keyring>>,
+    share_policy: SharePolicy,
+    online_shares: Arc<Mutex<HashSet<String>>>,
+    view_tokens: ViewTokens,
     received_complete_imgs: HashMap<String, BigMessage>,
-    pub own_shared_imgs: Arc<Mutex<HashMap<SocketAddr, Vec<(String, u32)>>>>,
+    state: StateStore, // persists the three maps below and online_shares
+    pub own_shared_imgs: Arc<Mutex<HashMap<SocketAddr, Vec<OwnShare>>>>,
     pub received_shared_imgs: Arc<Mutex<HashMap<SocketAddr, Vec<(String, u32)>>>>,
-    pub requests: Arc<Mutex<HashMap<String, Action>>>,
+    pub requests: Arc<Mutex<HashMap<String, Request>>>,
     pub low_res_imgs_tmp: Arc<Mutex<Vec<String>>>,
 }
 
@@ -60,7 +115,6 @@ impl ClientBackend {
             .expect("Failed to parse IP from input");
 
         let ip_to_clients: SocketAddr = SocketAddr::new(ip_to_cloud.ip(), ip_to_cloud.port() + 1);
-        let next_req_id = get_req_id_log(REQ_ID_LOG_FILEPATH);
 
         let cloud_socket: Arc<UdpSocket> = Arc::new(
             UdpSocket::bind(ip_to_cloud)
@@ -77,18 +131,58 @@ impl ClientBackend {
         info!("Clients Communication on {ip_to_clients}");
 
         let cloud_servers = get_cloud_servers(SERVERS_FILEPATH, mode);
+        let pixel_order = get_pixel_order(EMBEDDING_KEY_FILEPATH);
+
+        mkdir(PICS_ROOT_PATH);
+        let identity = Identity::load_or_create(
+            format!("{}/identity-{}.key", PICS_ROOT_PATH, ip_to_clients).as_str(),
+        );
+        let keyring =
+            Keyring::load(format!("{}/keyring-{}.cbor", PICS_ROOT_PATH, ip_to_clients).as_str());
+        // per client, so clients sharing a directory do not hand out each other's ids
+        let req_ids =
+            RequestIds::load(format!("{}/req_id-{}.txt", PICS_ROOT_PATH, ip_to_cloud).as_str());
+        let state =
+            StateStore::load(format!("{}/state-{}.cbor", PICS_ROOT_PATH, ip_to_clients).as_str());
+
+        // How images are shared:
+        // ONLINE_VIEWS=1 every view needs a one-time token from the owner, so revocations apply immediately
+        // WATERMARK=visible|invisible viewers stamp their identity on the images they view
+        // SHARE_EXPIRY=<duration> (e.g. 90m, 12h, 7d) shares expire that long after they are made
+        let share_policy = SharePolicy {
+            permissions: Permissions {
+                online_views: env::var("ONLINE_VIEWS").is_ok_and(|v| v == "1"),
+                watermark: match env::var("WATERMARK").as_deref() {
+                    Ok("visible") => Some(WatermarkMode::Visible),
+                    Ok("invisible") => Some(WatermarkMode::Invisible),
+                    _ => None,
+                },
+                ..Permissions::default()
+            },
+            duration: env::var("SHARE_EXPIRY")
+                .ok()
+                .and_then(|duration| parse_duration(duration.as_str())),
+        };
 
         ClientBackend {
             cloud_socket,
             client_socket,
-            next_req_id,
+            req_ids,
             mode: String::from(mode),
-            cloud_servers,
+            cloud_servers: Arc::new(Mutex::new(cloud_servers)),
             dir_of_serv: ClientDirOfService::new(),
+            dir_cache: Mutex::new(None),
+            pixel_order,
+            identity: Arc::new(identity),
+            keyring: Arc::new(Mutex::new(keyring)),
+            share_policy,
+            online_shares: state.online_shares.clone(),
+            view_tokens: Arc::new(Mutex::new(HashMap::new())),
             received_complete_imgs: HashMap::new(),
-            own_shared_imgs: Arc::new(Mutex::new(HashMap::new())),
-            received_shared_imgs: Arc::new(Mutex::new(HashMap::new())),
-            requests: Arc::new(Mutex::new(HashMap::new())),
+            own_shared_imgs: state.own_shared_imgs.clone(),
+            received_shared_imgs: state.received_shared_imgs.clone(),
+            requests: state.requests.clone(),
+            state,
             low_res_imgs_tmp: Arc::new(Mutex::new(Vec::new())),
         }
     }
@@ -100,11 +194,23 @@ impl ClientBackend {
         let received_shared_imgs = self.received_shared_imgs.clone();
         let requests = self.requests.clone();
         let low_res_img_tmp = self.low_res_imgs_tmp.clone();
-        ClientDirOfService::join(self.cloud_socket.clone(), self.cloud_servers.clone()).await;
+        let identity = self.identity.clone();
+        let keyring = self.keyring.clone();
+        let online_shares = self.online_shares.clone();
+        let view_tokens = self.view_tokens.clone();
+        let cloud_servers = self.cloud_servers.clone();
+        let state = self.state.clone();
+        ClientDirOfService::join(
+            self.cloud_socket.clone(),
+            self.cloud_servers.lock().await.clone(),
+            self.req_ids.next().await,
+        )
+        .await;
+        self.sync_view_grants().await;
         let pending_updates = self.query_pending_updates().await;
         if let Some(actions_map) = pending_updates {
             info!("Pending Updates: {:?}", actions_map);
-            for (key, value) in actions_map {
+            for (key, actions) in actions_map {
                 let partial_img_id_parts: Vec<&str> = key.split('&').collect();
                 let img_id = String::from(partial_img_id_parts[0])
                     + "&"
@@ -117,14 +223,63 @@ impl ClientBackend {
                     + "&"
                     + partial_img_id_parts[1];
                 let src_addr = partial_img_id_parts[0].parse::<SocketAddr>().unwrap();
-                ClientBackend::handle_update_access(
-                    img_id,
-                    value.clone(),
-                    src_addr,
-                    received_shared_imgs.clone(),
-                )
-                .await;
+                for (id, action) in actions {
+                    if !self.state.first_application(&id).await {
+                        info!("Already applied update {}", id);
+                        continue;
+                    }
+                    // receipts and requests are about our own images, src_addr is the recipient
+                    let own_img_id = format!(
+                        "{}&{}&{}",
+                        self.client_socket.local_addr().unwrap(),
+                        src_addr,
+                        partial_img_id_parts[1]
+                    );
+                    match action {
+                        Action::Viewed(remaining, viewed_at) => {
+                            ClientBackend::handle_view_receipt(
+                                own_img_id,
+                                remaining,
+                                viewed_at,
+                                own_shared_imgs.clone(),
+                                online_shares.clone(),
+                            )
+                            .await;
+                            continue;
+                        }
+                        Action::RequestImage(views, recipient_key) => {
+                            requests
+                                .lock()
+                                .await
+                                .insert(own_img_id, Request::Image(views, recipient_key));
+                            continue;
+                        }
+                        Action::RevokeConfirmed => {
+                            ClientBackend::handle_revoke_confirmed(
+                                own_img_id,
+                                own_shared_imgs.clone(),
+                            )
+                            .await;
+                            continue;
+                        }
+                        _ => {}
+                    }
+                    let revoked = ClientBackend::handle_update_access(
+                        img_id.clone(),
+                        action,
+                        src_addr,
+                        self.client_socket.local_addr().unwrap(),
+                        received_shared_imgs.clone(),
+                        keyring.clone(),
+                    )
+                    .await;
+                    if revoked {
+                        self.send_to_owner(&img_id, src_addr, Action::RevokeConfirmed)
+                            .await;
+                    }
+                }
             }
+            self.state.save().await;
         }
         let mut received_complete_imgs: HashMap<String, BigMessage> = HashMap::new();
 
@@ -148,6 +303,12 @@ impl ClientBackend {
                                 received_shared_imgs.clone(),
                                 requests.clone(),
                                 low_res_img_tmp.clone(),
+                                identity.clone(),
+                                keyring.clone(),
+                                online_shares.clone(),
+                                view_tokens.clone(),
+                                cloud_servers.lock().await.clone(),
+                                &state,
                             )
                             .await;
                         }
@@ -165,11 +326,26 @@ impl ClientBackend {
         msg: Msg,
         src_addr: SocketAddr,
         received_complete_imgs: &mut HashMap<String, BigMessage>,
-        own_shared_imgs: Arc<Mutex<HashMap<SocketAddr, Vec<(String, u32)>>>>,
+        own_shared_imgs: Arc<Mutex<HashMap<SocketAddr, Vec<OwnShare>>>>,
         received_shared_imgs: Arc<Mutex<HashMap<SocketAddr, Vec<(String, u32)>>>>,
-        requests: Arc<Mutex<HashMap<String, Action>>>,
+        requests: Arc<Mutex<HashMap<String, Request>>>,
         low_res_img_tmp: Arc<Mutex<Vec<String>>>,
+        identity: Arc<Identity>,
+        keyring: Arc<Mutex<Keyring>>,
+        online_shares: Arc<Mutex<HashSet<String>>>,
+        view_tokens: ViewTokens,
+        cloud_servers: Vec<(SocketAddr, SocketAddr)>,
+        state: &StateStore,
     ) {
+        // image transfers (saved once complete) and view tokens leave the state alone
+        let changes_state = matches!(
+            msg.msg_type,
+            Type::ImageRequest(..)
+                | Type::UpdateAccessRequest(..)
+                | Type::UpdateAccess(..)
+                | Type::ViewTokenRequest(..)
+                | Type::ViewGrant(..)
+        );
         match msg.msg_type {
             Type::LowResImgReq => {
                 ClientBackend::handle_low_res_imgs_req(client_socket.clone(), src_addr).await;
@@ -186,15 +362,14 @@ impl ClientBackend {
                 .await;
             }
 
-            Type::ImageRequest(img_name, requested_access) => {
-                ClientBackend::handle_image_request(
-                    img_name,
-                    requested_access,
-                    client_socket.clone(),
-                    src_addr,
-                    own_shared_imgs,
-                )
-                .await;
+            Type::ImageRequest(img_name, requested_access, recipient_key) => {
+                let img_id =
+                    request_img_id(client_socket.local_addr().unwrap(), src_addr, &img_name);
+                println!("Image request {} for {} view(s)", img_id, requested_access);
+                requests
+                    .lock()
+                    .await
+                    .insert(img_id, Request::Image(requested_access, recipient_key));
             }
 
             Type::Fragment(frag) => {
@@ -210,19 +385,22 @@ impl ClientBackend {
                     received_complete_imgs.remove(&req_id);
 
                     if let Ok(Msg {
-                        msg_type: Type::SharedImage(img_id, img, recieved_access),
+                        msg_type: Type::SharedImage(img_id, imgs, recieved_access),
                         ..
                     }) = serde_cbor::de::from_slice(&data)
                     {
                         ClientBackend::handle_shared_image(
                             req_id,
-                            img,
+                            imgs,
                             recieved_access,
                             client_socket.clone(),
                             src_addr,
                             received_shared_imgs,
+                            identity,
+                            keyring,
                         )
                         .await;
+                        state.save().await;
                     }
                 }
             }
@@ -238,7 +416,10 @@ impl ClientBackend {
             //     .await;
             // }
             Type::UpdateAccessRequest(img_id, action) => {
-                requests.lock().await.insert(img_id, action);
+                requests
+                    .lock()
+                    .await
+                    .insert(img_id, Request::Access(action));
                 // ClientBackend::handle_update_access_req(
                 //     img_id,
                 //     action,
@@ -248,12 +429,86 @@ impl ClientBackend {
                 // .await;
             }
 
+            // only the recipient itself can report its views
+            Type::UpdateAccess(img_id, Action::Viewed(remaining, viewed_at))
+                if img_id.split('&').nth(1) == Some(src_addr.to_string().as_str()) =>
+            {
+                ClientBackend::handle_view_receipt(
+                    img_id,
+                    remaining,
+                    viewed_at,
+                    own_shared_imgs,
+                    online_shares,
+                )
+                .await;
+            }
+            Type::UpdateAccess(_, Action::Viewed(..)) => {}
+
+            Type::UpdateAccess(img_id, Action::RevokeConfirmed)
+                if img_id.split('&').nth(1) == Some(src_addr.to_string().as_str()) =>
+            {
+                ClientBackend::handle_revoke_confirmed(img_id, own_shared_imgs).await;
+            }
+            Type::UpdateAccess(_, Action::RevokeConfirmed) => {}
+
             Type::UpdateAccess(img_id, action) => {
-                ClientBackend::handle_update_access(img_id, action, src_addr, received_shared_imgs)
-                    .await;
+                let revoked = ClientBackend::handle_update_access(
+                    img_id.clone(),
+                    action,
+                    src_addr,
+                    client_socket.local_addr().unwrap(),
+                    received_shared_imgs,
+                    keyring,
+                )
+                .await;
+                // the owner sent the revocation itself, so it is online
+                if revoked {
+                    let msg = Msg {
+                        sender: client_socket.local_addr().unwrap(),
+                        receiver: src_addr,
+                        msg_type: Type::UpdateAccess(img_id, Action::RevokeConfirmed),
+                        payload: None,
+                    };
+                    let serialized_msg = serde_cbor::ser::to_vec(&msg).unwrap();
+                    client_socket
+                        .send_to(&serialized_msg, src_addr)
+                        .await
+                        .unwrap();
+                }
+            }
+
+            Type::ViewTokenRequest(img_id, nonce) => {
+                ClientBackend::handle_view_token_request(
+                    img_id,
+                    nonce,
+                    client_socket.clone(),
+                    src_addr,
+                    own_shared_imgs,
+                    online_shares,
+                    cloud_servers,
+                    identity,
+                )
+                .await;
+            }
+
+            Type::ViewToken(img_id, nonce, token) => {
+                if let Some(tx) = view_tokens.lock().await.remove(&nonce) {
+                    let _ = tx.send(token);
+                }
+            }
+
+            // the cloud granted a view while we could not be reached, under our own grant
+            Type::ViewGrant((grant, remaining)) => {
+                let online = online_shares.lock().await.contains(&grant.value.img_id);
+                if online && grant.verify(&identity.public_key()) {
+                    count_cloud_views(&own_shared_imgs, &grant.value, remaining).await;
+                }
             }
             _ => {}
         }
+        if changes_state {
+            state.save().await;
+        }
     }
 
     pub async fn request_low_res_images(&self, client_addr: SocketAddr) {
@@ -327,7 +582,7 @@ impl ClientBackend {
         let socket = self.cloud_socket.clone();
         let mode = self.mode.clone();
 
-        let servers = self.cloud_servers.clone();
+        let servers = self.cloud_servers.lock().await.clone();
         let mut buffer = [0; 1024];
 
         for server in &servers {
@@ -348,6 +603,7 @@ impl ClientBackend {
         let sleep = sleep(Duration::from_millis(5000));
         tokio::pin!(sleep);
 
+        let mut refusals = 0;
         for _ in 0..10 {
             tokio::select! {
                     _ = &mut sleep => {
@@ -356,12 +612,20 @@ impl ClientBackend {
                     recv_result = socket.recv_from(&mut buffer) => {
                         match recv_result {
                             Ok((_bytes_read, src_addr)) => {
-                                if let Ok(response) = std::str::from_utf8(&buffer[.._bytes_read]) {
-                                    let chosen_server: SocketAddr = response.parse().unwrap();
-                                    info!("{}", chosen_server);
-                                    return Some(chosen_server);
-                                } else {
-                                    continue;
+                                match serde_cbor::de::from_slice::<Msg>(&buffer[.._bytes_read]) {
+                                    Ok(Msg { msg_type: Type::ServerAssignment(chosen_server, assigned_servers), .. }) => {
+                                        info!("{}", chosen_server);
+                                        self.refresh_cloud_servers(assigned_servers).await;
+                                        return Some(chosen_server);
+                                    }
+                                    Ok(Msg { msg_type: Type::Refused(reason), .. }) => {
+                                        println!("Server {} refused the request: {}", src_addr, reason);
+                                        refusals += 1;
+                                        if refusals == servers.len() {
+                                            return None;
+                                        }
+                                    }
+                                    _ => continue,
                                 }
                             }
                             Err(e) => {
@@ -374,6 +638,28 @@ impl ClientBackend {
         None
     }
 
+    // Every server reply carries the current cloud servers, servers that joined
+    // are subscribed to and those that left are dropped.
+    async fn refresh_cloud_servers(&self, servers: Vec<(SocketAddr, SocketAddr)>) {
+        let mut cloud_servers = self.cloud_servers.lock().await;
+        if servers.is_empty() || *cloud_servers == servers {
+            return;
+        }
+        let joined: Vec<_> = servers
+            .iter()
+            .filter(|server| !cloud_servers.contains(server))
+            .cloned()
+            .collect();
+        println!("Cloud servers now {:?}", servers);
+        *cloud_servers = servers;
+        drop(cloud_servers);
+        if !joined.is_empty() {
+            ClientDirOfService::join(self.cloud_socket.clone(), joined, self.req_ids.next().await)
+                .await;
+        }
+    }
+
+    // The owner approves image requests, offline owners find them in their pending updates.
     pub async fn send_image_request(
         &self,
         img_name: String,
@@ -381,10 +667,42 @@ impl ClientBackend {
         peer_client_addr: SocketAddr,
     ) {
         println!("Send Image Request");
+        let owner_online = match self.query_dir_of_serv().await {
+            Some(dir_of_serv_map) => *dir_of_serv_map.get(&peer_client_addr).unwrap_or(&false),
+            None => true,
+        };
+        if !owner_online {
+            println!(
+                "Owner {} is offline, leaving the request with the cloud",
+                peer_client_addr
+            );
+            let img_id = request_img_id(
+                peer_client_addr,
+                self.client_socket.local_addr().unwrap(),
+                &img_name,
+            );
+            let action = Action::RequestImage(requested_access, self.identity.public_key());
+            let request = self.req_ids.next().await;
+            for server in &self.cloud_servers.lock().await.clone() {
+                let msg = Msg {
+                    sender: self.cloud_socket.local_addr().unwrap(),
+                    receiver: server.0,
+                    msg_type: Type::UpdateAccess(img_id.clone(), action.clone()),
+                    payload: Some(request.to_string()),
+                };
+                let serialized_msg = serde_cbor::ser::to_vec(&msg).unwrap();
+                self.cloud_socket
+                    .send_to(&serialized_msg, server.0)
+                    .await
+                    .unwrap();
+            }
+            return;
+        }
+
         let msg = Msg {
             sender: self.client_socket.local_addr().unwrap(),
             receiver: peer_client_addr,
-            msg_type: Type::ImageRequest(img_name, requested_access),
+            msg_type: Type::ImageRequest(img_name, requested_access, self.identity.public_key()),
             payload: None,
         };
         let serialized_msg = serde_cbor::ser::to_vec(&msg).unwrap();
@@ -394,44 +712,130 @@ impl ClientBackend {
             .unwrap();
     }
 
+    // Shares the image of an approved request (img_id is owner&requester&name),
+    // possibly with fewer views than requested.
+    pub async fn approve_image_request(&self, img_id: &str, views: u32, recipient_key: [u8; 32]) {
+        let parts: Vec<&str> = img_id.split('&').collect();
+        let recipient: SocketAddr = parts[1].parse().unwrap();
+        ClientBackend::handle_image_request(
+            String::from(parts[2]),
+            views,
+            recipient_key,
+            self.client_socket.clone(),
+            recipient,
+            self.own_shared_imgs.clone(),
+            self.identity.clone(),
+            self.pixel_order,
+            self.share_policy.clone(),
+            self.online_shares.clone(),
+            self.cloud_servers.lock().await.clone(),
+        )
+        .await;
+        self.state.save().await;
+    }
+
+    // drops a request from View Requests, once answered
+    pub async fn remove_request(&self, img_id: &str) {
+        self.requests.lock().await.remove(img_id);
+        self.state.save().await;
+    }
+
     async fn handle_image_request(
         img_name: String,
         requested_access: u32,
+        recipient_key: [u8; 32],
         client_socket: Arc<UdpSocket>,
         src_addr: SocketAddr,
-        own_shared_imgs: Arc<Mutex<HashMap<SocketAddr, Vec<(String, u32)>>>>,
+        own_shared_imgs: Arc<Mutex<HashMap<SocketAddr, Vec<OwnShare>>>>,
+        identity: Arc<Identity>,
+        pixel_order: PixelOrder,
+        policy: SharePolicy,
+        online_shares: Arc<Mutex<HashSet<String>>>,
+        cloud_servers: Vec<(SocketAddr, SocketAddr)>,
     ) {
         println!("Handle Image Request");
         let img_parts: Vec<&str> = img_name.split('.').collect();
         let path = format!("{}/{}.png", ENCRYPTED_PICS_PATH, img_parts.first().unwrap());
         if file_exists(path.as_str()) {
-            let img_buffer = file_as_image_buffer(path);
+            let img_buffers = match load_parts(path.as_str()) {
+                Some(img_buffers) => img_buffers,
+                None => {
+                    println!("Image {} is corrupted or has missing parts", path);
+                    return;
+                }
+            };
+
+            //Sealing the hidden image to the recipient's identity key
+            let sealed = rewrite_parts(img_buffers, pixel_order, move |secret| {
+                identity::seal(&recipient_key, secret)
+            })
+            .await;
+            let (mut img_buffers, (ephemeral_key, tag)) = match sealed {
+                Some((img_buffers, Some(sealing))) => (img_buffers, sealing),
+                Some((_, None)) => {
+                    println!("Invalid identity key from {}", src_addr);
+                    return;
+                }
+                None => {
+                    println!("Image {} is corrupted or has missing parts", path);
+                    return;
+                }
+            };
 
-            //Embedding the access limit in the image
-            let mut img_buffer_with_access = img_buffer.clone();
-            let (width, height) = img_buffer_with_access.dimensions();
-            let access_pixel = img_buffer_with_access.get_pixel_mut(width - 1, height - 1);
-            access_pixel[3] = requested_access as u8; //set the alpha channel of the last pixel to the access limit
+            let pic_id = format!(
+                "{}&{}&{}.png",
+                client_socket.local_addr().unwrap(),
+                src_addr,
+                img_parts.first().unwrap()
+            );
 
-            let image = Image {
-                dims: img_buffer_with_access.dimensions(),
-                data: img_buffer_with_access.into_raw(),
+            //Embedding the signed access manifest in the image (first part)
+            let expiry = policy.duration.map(|duration| now_secs() + duration);
+            let manifest = AccessManifest {
+                owner: client_socket.local_addr().unwrap(),
+                recipient: src_addr,
+                recipient_key,
+                ephemeral_key,
+                tag,
+                img_name: format!("{}.png", img_parts.first().unwrap()),
+                views: requested_access,
+                expiry,
+                permissions: policy.permissions.clone(),
+                issued_at: now_secs(),
+            };
+            let serial = next_serial(&own_shared_imgs, &pic_id).await;
+            let terms = ShareTerms {
+                img_id: pic_id.clone(),
+                views: requested_access,
+                expiry,
+                revoked: false,
+                serial,
             };
+            let record = ManifestRecord::new(
+                SignedManifest::sign(manifest, &identity),
+                Signed::sign(terms, &identity),
+            );
+            if !record.write(&mut img_buffers[0]) {
+                println!("Image {} is too small to carry an access manifest", path);
+                return;
+            }
+
+            let images: Vec<Image> = img_buffers
+                .into_iter()
+                .map(|img_buffer| Image {
+                    dims: img_buffer.dimensions(),
+                    data: img_buffer.into_raw(),
+                })
+                .collect();
 
             let msg = Msg {
                 sender: client_socket.local_addr().unwrap(),
                 receiver: src_addr,
-                msg_type: Type::SharedImage(img_name.clone(), image, requested_access),
+                msg_type: Type::SharedImage(img_name.clone(), images, requested_access),
                 payload: None,
             };
 
             let serialized_msg = serde_cbor::ser::to_vec(&msg).unwrap();
-            let pic_id = format!(
-                "{}&{}&{}.png",
-                client_socket.local_addr().unwrap(),
-                src_addr,
-                img_parts.first().unwrap()
-            );
 
             fragment::client_send(
                 serialized_msg,
@@ -443,13 +847,28 @@ impl ClientBackend {
             .await;
             println!("Finished sending pic");
 
-            let mut guard = own_shared_imgs.lock().await;
-            let entry = guard.entry(src_addr).or_insert(Vec::new());
-            if let Some(index) = entry.iter().position(|(s, _)| s == &pic_id) {
-                entry[index] = (pic_id, requested_access);
+            if policy.permissions.online_views {
+                online_shares.lock().await.insert(pic_id.clone());
+                let grant = OnlineGrant {
+                    img_id: pic_id.clone(),
+                    owner_key: identity.public_key(),
+                    nonce: None,
+                    remaining: requested_access,
+                    serial,
+                };
+                send_view_grant(
+                    &client_socket,
+                    &cloud_servers,
+                    &Signed::sign(grant, &identity),
+                )
+                .await;
             } else {
-                entry.push((pic_id, requested_access));
+                // a share made again without online views is counted by its recipient
+                online_shares.lock().await.remove(&pic_id);
             }
+            set_own_share(&own_shared_imgs, pic_id.clone(), requested_access).await;
+            set_share_status(&own_shared_imgs, &pic_id, ShareStatus::Active).await;
+            set_share_expiry(&own_shared_imgs, &pic_id, expiry).await;
         } else {
             println!("File does not exist: {}", path);
         }
@@ -457,11 +876,13 @@ impl ClientBackend {
 
     async fn handle_shared_image(
         pic_id: String,
-        img: Image,
+        imgs: Vec<Image>,
         recieved_access: u32,
         client_socket: Arc<UdpSocket>,
         src_addr: SocketAddr,
         received_shared_imgs: Arc<Mutex<HashMap<SocketAddr, Vec<(String, u32)>>>>,
+        identity: Arc<Identity>,
+        keyring: Arc<Mutex<Keyring>>,
     ) {
         println!("Handle Share Image");
         println!("Image ID: {}", pic_id);
@@ -474,12 +895,41 @@ impl ClientBackend {
         mkdir(path_encrypted.as_str());
         // mkdir(path_decoded.as_str());
 
-        let image_buffer: image::ImageBuffer<Rgba<u8>, Vec<u8>> =
-            image::ImageBuffer::from_raw(img.dims.0, img.dims.1, img.data).unwrap();
+        let image_buffers: Vec<ImageBuffer<Rgba<u8>, Vec<u8>>> = imgs
+            .into_iter()
+            .map(|img| image::ImageBuffer::from_raw(img.dims.0, img.dims.1, img.data).unwrap())
+            .collect();
+
+        // Pin the owner's key on first contact and refuse images whose manifest does not check out
+        let record = match image_buffers.first().and_then(ManifestRecord::read) {
+            Some(record) => record,
+            None => {
+                println!("Rejecting {}: {}", pic_id, AccessDenied::NoManifest);
+                return;
+            }
+        };
+        let mut keyring = keyring.lock().await;
+        if !keyring.pin(src_addr, record.grant.owner_key) {
+            println!("Rejecting {}: {}", pic_id, AccessDenied::UnknownOwner);
+            return;
+        }
+        if let Err(e) = record.check_grant(
+            keyring.get(&src_addr),
+            src_addr,
+            client_socket.local_addr().unwrap(),
+            &identity.public_key(),
+            pic_name,
+            now_secs(),
+        ) {
+            println!("Rejecting {}: {}", pic_id, e);
+            return;
+        }
+        drop(keyring);
+        let recieved_access = record.remaining;
 
-        save_image_buffer(
-            image_buffer.clone(),
-            format!("{}/{}", path_encrypted, pic_name),
+        save_parts(
+            image_buffers,
+            format!("{}/{}", path_encrypted, pic_name).as_str(),
         );
 
         let mut guard = received_shared_imgs.lock().await;
@@ -491,53 +941,98 @@ impl ClientBackend {
         }
     }
 
-    pub async fn view_image(&self, img_name: String, src_addr: SocketAddr) -> bool {
+    // Err if the image was not shown, NoViews when more views can be requested
+    pub async fn view_image(
+        &self,
+        img_name: String,
+        src_addr: SocketAddr,
+    ) -> Result<(), AccessDenied> {
         let path = format!("{}/{}/{}", ENCRYPTED_PICS_PATH, src_addr, img_name);
-        let mut img_buffer = file_as_image_buffer(path.clone());
-        let (width, height) = img_buffer.dimensions();
-        let access_pixel = img_buffer.get_pixel_mut(width - 1, height - 1);
-        let access = access_pixel[3];
-        println!("Access: {}", access);
-
-        //Check if there is still access to the image
-        if access == 0 {
-            return false;
+        let mut img_buffers = load_parts(path.as_str()).ok_or(AccessDenied::Corrupted)?;
+        let own_addr = self.client_socket.local_addr().unwrap();
+
+        //Check the manifest and whether there is still access to the image
+        let mut record = ManifestRecord::read(&img_buffers[0]).ok_or(AccessDenied::NoManifest)?;
+        let owner_key = self.keyring.lock().await.get(&src_addr);
+        let own_key = self.identity.public_key();
+        record.check_grant(
+            owner_key,
+            src_addr,
+            own_addr,
+            &own_key,
+            &img_name,
+            now_secs(),
+        )?;
+        if let Some(expiry) = record.terms.value.expiry {
+            println!("Access expires: {}", format_time(expiry));
+        }
+        let online_views = record.grant.manifest.permissions.online_views;
+        let watermark_mode = record.grant.manifest.permissions.watermark;
+        println!("Access: {}", record.remaining);
+        if !online_views && record.remaining == 0 {
+            return Err(AccessDenied::NoViews);
         }
 
-        access_pixel[3] -= 1;
+        let mut secret_bytes = decode_parts(img_buffers.clone(), self.pixel_order)
+            .await
+            .ok_or(AccessDenied::Corrupted)?;
+        let manifest = &record.grant.manifest;
+        if !self
+            .identity
+            .open(&manifest.ephemeral_key, &mut secret_bytes, &manifest.tag)
+        {
+            return Err(AccessDenied::WrongIdentity);
+        }
+        let decoded_buffer =
+            image::load_from_memory(&secret_bytes).map_err(|_| AccessDenied::Corrupted)?;
+
+        let img_id = format!("{}&{}&{}", src_addr, own_addr, img_name);
+        if online_views {
+            match self.request_view_token(&img_id, src_addr).await {
+                Some(Some(remaining)) => record.remaining = remaining.min(record.terms.value.views),
+                Some(None) => {
+                    println!("The owner did not grant a view of {}", img_name);
+                    record.remaining = 0;
+                    record.write(&mut img_buffers[0]);
+                    save_image_buffer(img_buffers.swap_remove(0), path.clone());
+                    return Err(AccessDenied::NoViews);
+                }
+                None => return Err(AccessDenied::Unreachable),
+            }
+        } else {
+            record.remaining -= 1;
+        }
+        record.write(&mut img_buffers[0]);
+        let viewed_at = now_secs();
 
-        let img_id = format!(
-            "{}&{}&{}",
-            src_addr,
-            self.client_socket.local_addr().unwrap(),
-            img_name
-        );
-        println!("Before");
         let mut guard = self.received_shared_imgs.lock().await;
-        println!("After");
-        let new_access = access_pixel[3];
         let entry = guard.entry(src_addr).or_insert(Vec::new());
         if let Some(index) = entry.iter().position(|(s, _)| s == &img_id) {
-            entry[index] = (img_id, new_access as u32);
+            entry[index] = (img_id.clone(), record.remaining);
         } else {
-            entry.push((img_id, new_access as u32));
+            entry.push((img_id.clone(), record.remaining));
         }
         drop(guard);
-
-        let secret_bytes = decode_img(img_buffer.clone()).await;
-        let decoded_buffer = image::load_from_memory(&secret_bytes).unwrap();
-        let decoded_buffer = decoded_buffer.to_rgba8();
+        self.state.save().await;
+
+        let mut decoded_buffer = decoded_buffer.to_rgba8();
+        if let Some(mode) = watermark_mode {
+            let mark = Watermark {
+                viewer_key: own_key,
+                viewer: own_addr,
+                timestamp: now_secs(),
+            };
+            watermark::stamp(&mut decoded_buffer, &mark, mode);
+        }
         let (width_decoded, height_decoded) = decoded_buffer.dimensions();
 
-        save_image_buffer(img_buffer, path.clone());
+        save_image_buffer(img_buffers.swap_remove(0), path.clone());
 
         let name_without_ext = img_name.split('.').collect::<Vec<&str>>()[0];
         // Set the title and size of the window
         let viewer_title = format!(
             "Image from: {:?}\t\t\t\t\tImage name: {}\t\t\t\t\tRemaining Views: {}",
-            src_addr,
-            name_without_ext,
-            access - 1
+            src_addr, name_without_ext, record.remaining
         );
 
         // Create a window to display the image
@@ -565,7 +1060,37 @@ impl ClientBackend {
                 .update_with_buffer(&buffer, width_decoded as usize, height_decoded as usize)
                 .expect("Failed to update window");
         }
-        true
+
+        self.send_to_owner(
+            &img_id,
+            src_addr,
+            Action::Viewed(record.remaining, viewed_at),
+        )
+        .await;
+        Ok(())
+    }
+
+    pub fn verify_watermark(&self, path: &str) {
+        let img = match image::open(path) {
+            Ok(img) => img.to_rgba8(),
+            Err(e) => {
+                println!("Cannot open {}: {}", path, e);
+                return;
+            }
+        };
+        match watermark::extract(&img) {
+            Some((mark, mode)) => println!(
+                "{:?} watermark: viewed by {} (key {}) at {} (unix time)",
+                mode,
+                mark.viewer,
+                mark.viewer_key
+                    .iter()
+                    .map(|b| format!("{:02x}", b))
+                    .collect::<String>(),
+                mark.timestamp
+            ),
+            None => println!("No watermark found in {}", path),
+        }
     }
 
     pub async fn request_update_access(
@@ -621,12 +1146,17 @@ impl ClientBackend {
             .unwrap();
     }
 
+    // Applies the owner's new terms for an image shared with us (recipient), true
+    // if they revoke it and our copy is gone (the owner is then owed a confirmation).
+    // Only terms signed by the owner's pinned key are applied.
     async fn handle_update_access(
         img_id: String,
         action: Action,
         src_addr: SocketAddr,
+        recipient: SocketAddr,
         received_shared_imgs: Arc<Mutex<HashMap<SocketAddr, Vec<(String, u32)>>>>,
-    ) {
+        keyring: Arc<Mutex<Keyring>>,
+    ) -> bool {
         println!("Handle Update Access");
         println!("Image ID: {}", img_id);
         println!("Image New Access: {:?}", action);
@@ -634,36 +1164,83 @@ impl ClientBackend {
         let img_id_parts: Vec<&str> = img_id.split('&').collect();
         let img_name = img_id_parts.last().unwrap();
 
+        let signed = match action {
+            Action::Terms(signed) => signed,
+            _ => {
+                println!("Ignoring unsigned update of {}", img_id);
+                return false;
+            }
+        };
+        let owner_key = keyring.lock().await.get(&src_addr);
+        if !owner_key.is_some_and(|key| signed.verify(&key))
+            || signed.value.img_id != img_id
+            || img_id != format!("{}&{}&{}", src_addr, recipient, img_name)
+        {
+            println!(
+                "Ignoring update of {}: {}",
+                img_id,
+                AccessDenied::BadSignature
+            );
+            return false;
+        }
+
         let path = format!("{}/{}/{}", ENCRYPTED_PICS_PATH, src_addr, img_name);
 
+        if signed.value.revoked {
+            // only a copy that was there and is now gone is confirmed to the owner
+            let deleted = match delete_parts(&path) {
+                Ok(0) => {
+                    println!("No copy of {} to delete", img_name);
+                    false
+                }
+                Ok(parts) => {
+                    println!(
+                        "Deleted {} ({} part(s)), access revoked by {}",
+                        img_name, parts, src_addr
+                    );
+                    true
+                }
+                Err(e) => {
+                    println!("Failed to delete {}: {}", img_name, e);
+                    return false;
+                }
+            };
+            if let Some(entry) = received_shared_imgs.lock().await.get_mut(&src_addr) {
+                entry.retain(|(s, _)| s != &img_id);
+            }
+            return deleted;
+        }
+
         if file_exists(path.as_str()) {
             let mut img_buffer = file_as_image_buffer(path.clone());
-            let (width, height) = img_buffer.dimensions();
-            let access_pixel = img_buffer.get_pixel_mut(width - 1, height - 1);
-
-            match action {
-                Action::Increment(n) => access_pixel[3] += n as u8,
-                Action::Decrement(n) => {
-                    if n as u8 > access_pixel[3] {
-                        access_pixel[3] = 0
-                    } else {
-                        access_pixel[3] -= n as u8
-                    }
+            let mut record = match ManifestRecord::read(&img_buffer) {
+                Some(record) => record,
+                None => {
+                    println!("Cannot update {}: {}", img_id, AccessDenied::NoManifest);
+                    return false;
                 }
-                Action::Revoke => access_pixel[3] = 0,
+            };
+            if signed.value.serial <= record.terms.value.serial {
+                println!("Ignoring outdated terms for {}", img_id);
+                return false;
             }
-            let updated_access_num = access_pixel[3];
 
+            record.remaining = signed.value.views;
+            record.terms = signed;
+            let updated_access_num = record.remaining;
+
+            record.write(&mut img_buffer);
             save_image_buffer(img_buffer, path.clone());
 
             let mut guard = received_shared_imgs.lock().await;
             let entry = guard.entry(src_addr).or_insert(Vec::new());
             if let Some(index) = entry.iter().position(|(s, _)| s == &img_id) {
-                entry[index] = (img_id, updated_access_num as u32);
+                entry[index] = (img_id, updated_access_num);
             } else {
-                entry.push((img_id, updated_access_num as u32));
+                entry.push((img_id, updated_access_num));
             }
         }
+        false
     }
 
     pub async fn send_update_access_to_client(
@@ -681,11 +1258,19 @@ impl ClientBackend {
             img_name
         );
 
+        let terms = match self.update_own_share(img_id.clone(), &action).await {
+            Some(terms) => terms,
+            None => {
+                println!("Not sharing {} with {}", img_name, peer_client_addr);
+                return;
+            }
+        };
+
         println!("Sending update access to client {:?}", peer_client_addr);
         let msg = Msg {
             sender: socket.local_addr().unwrap(),
             receiver: peer_client_addr.parse::<SocketAddr>().unwrap(),
-            msg_type: Type::UpdateAccess(img_id.clone(), action.clone()),
+            msg_type: Type::UpdateAccess(img_id.clone(), Action::Terms(terms)),
             payload: None,
         };
 
@@ -696,13 +1281,113 @@ impl ClientBackend {
             .unwrap();
     }
 
+    // Sends a receipt or confirmation about the owner's image back to the owner,
+    // through the cloud if the owner is offline. The directory is only queried
+    // when the cached one is stale, and the sending happens in the background.
+    async fn send_to_owner(&self, img_id: &str, owner: SocketAddr, action: Action) {
+        let cached = match self.dir_cache.lock().await.as_ref() {
+            Some((at, dir_of_serv_map)) if at.elapsed().as_secs() < DIR_CACHE_SECS => {
+                Some(dir_of_serv_map.clone())
+            }
+            _ => None,
+        };
+        let dir_of_serv_map = match cached {
+            Some(dir_of_serv_map) => Some(dir_of_serv_map),
+            None => self.query_dir_of_serv().await,
+        };
+        let owner_online = match dir_of_serv_map {
+            Some(dir_of_serv_map) => *dir_of_serv_map.get(&owner).unwrap_or(&false),
+            None => true,
+        };
+
+        let (socket, targets, request) = if owner_online {
+            (self.client_socket.clone(), vec![owner], None)
+        } else {
+            println!(
+                "Owner {} is offline, leaving {:?} with the cloud",
+                owner, action
+            );
+            let servers = self
+                .cloud_servers
+                .lock()
+                .await
+                .iter()
+                .map(|server| server.0)
+                .collect();
+            let request = self.req_ids.next().await;
+            (
+                self.cloud_socket.clone(),
+                servers,
+                Some(request.to_string()),
+            )
+        };
+        let img_id = String::from(img_id);
+        tokio::spawn(async move {
+            for target in targets {
+                let msg = Msg {
+                    sender: socket.local_addr().unwrap(),
+                    receiver: target,
+                    msg_type: Type::UpdateAccess(img_id.clone(), action.clone()),
+                    payload: request.clone(),
+                };
+                let serialized_msg = serde_cbor::ser::to_vec(&msg).unwrap();
+                if let Err(e) = socket.send_to(&serialized_msg, target).await {
+                    println!("Failed to send {:?} to {}: {}", action, target, e);
+                }
+            }
+        });
+    }
+
+    // Owner side of a view receipt. The recipient's count is the real one, except
+    // for online shares where we count the views ourselves.
+    async fn handle_view_receipt(
+        img_id: String,
+        remaining: u32,
+        viewed_at: u64,
+        own_shared_imgs: Arc<Mutex<HashMap<SocketAddr, Vec<OwnShare>>>>,
+        online_shares: Arc<Mutex<HashSet<String>>>,
+    ) {
+        println!("Handle View Receipt");
+        println!("Image ID: {}", img_id);
+        println!(
+            "Viewed at {}, {} view(s) left",
+            format_time(viewed_at),
+            remaining
+        );
+
+        let online = online_shares.lock().await.contains(&img_id);
+        if !online {
+            set_own_share(&own_shared_imgs, img_id.clone(), remaining).await;
+        }
+        let recipient = match img_id.split('&').nth(1).map(|addr| addr.parse()) {
+            Some(Ok(recipient)) => recipient,
+            _ => return,
+        };
+        let mut guard = own_shared_imgs.lock().await;
+        if let Some(entry) = guard.get_mut(&recipient) {
+            if let Some(share) = entry.iter_mut().find(|share| share.img_id == img_id) {
+                share.last_viewed = share.last_viewed.max(Some(viewed_at));
+            }
+        }
+    }
+
+    // Owner side of a revocation: the recipient's copy is gone. The share is kept
+    // (marked confirmed) so the owner can see the revocation went through.
+    async fn handle_revoke_confirmed(
+        img_id: String,
+        own_shared_imgs: Arc<Mutex<HashMap<SocketAddr, Vec<OwnShare>>>>,
+    ) {
+        println!("Revocation of {} confirmed", img_id);
+        set_share_status(&own_shared_imgs, &img_id, ShareStatus::RevokedConfirmed).await;
+    }
+
     pub async fn send_update_access_to_cloud(
         &self,
         img_name: String,
         action: Action,
         peer_client_addr: String,
     ) {
-        let servers = self.cloud_servers.clone();
+        let servers = self.cloud_servers.lock().await.clone();
         // let mut buffer = [0; 1024];
         let socket = self.cloud_socket.clone();
 
@@ -712,7 +1397,15 @@ impl ClientBackend {
             peer_client_addr,
             img_name
         );
+        let terms = match self.update_own_share(img_id.clone(), &action).await {
+            Some(terms) => terms,
+            None => {
+                println!("Not sharing {} with {}", img_name, peer_client_addr);
+                return;
+            }
+        };
 
+        let request = self.req_ids.next().await;
         for server in &servers {
             let target_addr = server.0;
 
@@ -720,8 +1413,8 @@ impl ClientBackend {
             let msg = Msg {
                 sender: socket.local_addr().unwrap(),
                 receiver: target_addr,
-                msg_type: Type::UpdateAccess(img_id.clone(), action.clone()),
-                payload: None,
+                msg_type: Type::UpdateAccess(img_id.clone(), Action::Terms(terms.clone())),
+                payload: Some(request.to_string()),
             };
             let serialized_msg = serde_cbor::ser::to_vec(&msg).unwrap();
             // let serialized_msg = serde_json::to_string(&msg).unwrap();
@@ -748,13 +1441,13 @@ impl ClientBackend {
         // }
     }
 
-    pub async fn query_pending_updates(&self) -> Option<HashMap<String, Action>> {
+    pub async fn query_pending_updates(&self) -> Option<HashMap<String, Vec<(String, Action)>>> {
         if let Some(chosen_server) = self
-            .send_init_request_to_cloud(Type::ClientRequest(1))
+            .send_init_request_to_cloud(Type::ClientRequest(self.req_ids.next().await))
             .await
         {
             ClientDirOfService::query_pending(self.cloud_socket.clone(), chosen_server).await;
-            let mut buffer = [0; 2048];
+            let mut buffer = [0; BUFFER_SIZE];
 
             println!("Waiting for pending updates status from cloud");
             let sleep = sleep(Duration::from_millis(500));
@@ -774,7 +1467,7 @@ impl ClientBackend {
                                         ..
                                     }) => return r,
                                     Ok(_) => continue,
-                                    Err(e) => continue,
+                                    Err(_) => continue,
                                 }
                             }
                             Err(e) => {
@@ -790,9 +1483,243 @@ impl ClientBackend {
         None
     }
 
+    // Applies an owner side access change to the share's counter, which is the
+    // authoritative one for online shares (and so mirrored to the cloud), and signs
+    // the share's new terms for the recipient. None if there is no such share.
+    pub async fn update_own_share(
+        &self,
+        img_id: String,
+        action: &Action,
+    ) -> Option<Signed<ShareTerms>> {
+        let parts: Vec<&str> = img_id.split('&').collect();
+        let recipient = parts.get(1)?.parse::<SocketAddr>().ok()?;
+        let mut guard = self.own_shared_imgs.lock().await;
+        let share = guard
+            .get_mut(&recipient)?
+            .iter_mut()
+            .find(|share| share.img_id == img_id)?;
+        share.remaining = action.apply_views(share.remaining);
+        share.expiry = action.apply_expiry(share.expiry);
+        share.serial += 1;
+        if let Action::Revoke = action {
+            share.status = ShareStatus::Revoked;
+        }
+        let terms = ShareTerms {
+            img_id: img_id.clone(),
+            views: share.remaining,
+            expiry: share.expiry,
+            revoked: matches!(action, Action::Revoke),
+            serial: share.serial,
+        };
+        drop(guard);
+
+        if self.online_shares.lock().await.contains(&img_id) {
+            let grant = OnlineGrant {
+                img_id: img_id.clone(),
+                owner_key: self.identity.public_key(),
+                nonce: None,
+                remaining: terms.views,
+                serial: terms.serial,
+            };
+            let cloud_servers = self.cloud_servers.lock().await.clone();
+            let grant = Signed::sign(grant, &self.identity);
+            send_view_grant(&self.client_socket, &cloud_servers, &grant).await;
+        }
+        self.state.save().await;
+        Some(Signed::sign(terms, &self.identity))
+    }
+
+    // Owner side of online views: one view per token, counted down at the source.
+    // The token is a grant signed for the recipient's request, also handed to the
+    // cloud so it can go on granting the views left while we are offline.
+    async fn handle_view_token_request(
+        img_id: String,
+        nonce: u64,
+        client_socket: Arc<UdpSocket>,
+        src_addr: SocketAddr,
+        own_shared_imgs: Arc<Mutex<HashMap<SocketAddr, Vec<OwnShare>>>>,
+        online_shares: Arc<Mutex<HashSet<String>>>,
+        cloud_servers: Vec<(SocketAddr, SocketAddr)>,
+        identity: Arc<Identity>,
+    ) {
+        let parts: Vec<&str> = img_id.split('&').collect();
+        let valid = parts.len() == 3
+            && parts[0] == client_socket.local_addr().unwrap().to_string()
+            && parts[1] == src_addr.to_string()
+            && online_shares.lock().await.contains(&img_id);
+
+        let mut grant = None;
+        if valid {
+            let mut guard = own_shared_imgs.lock().await;
+            if let Some(entry) = guard.get_mut(&src_addr) {
+                if let Some(share) = entry.iter_mut().find(|share| share.img_id == img_id) {
+                    if share.remaining > 0 {
+                        share.remaining -= 1;
+                        share.serial += 1;
+                        grant = Some(OnlineGrant {
+                            img_id: img_id.clone(),
+                            owner_key: identity.public_key(),
+                            nonce: Some(nonce),
+                            remaining: share.remaining,
+                            serial: share.serial,
+                        });
+                    }
+                }
+            }
+        }
+        let grant = grant.map(|grant| Signed::sign(grant, &identity));
+        println!(
+            "View token for {}: {:?}",
+            img_id,
+            grant.as_ref().map(|grant| grant.value.remaining)
+        );
+
+        let token = grant
+            .clone()
+            .map(|grant| (grant.clone(), grant.value.remaining));
+        let msg = Msg {
+            sender: client_socket.local_addr().unwrap(),
+            receiver: src_addr,
+            msg_type: Type::ViewToken(img_id.clone(), nonce, token),
+            payload: None,
+        };
+        let serialized_msg = serde_cbor::ser::to_vec(&msg).unwrap();
+        client_socket
+            .send_to(&serialized_msg, src_addr)
+            .await
+            .unwrap();
+
+        if let Some(grant) = grant {
+            send_view_grant(&client_socket, &cloud_servers, &grant).await;
+        }
+    }
+
+    // Asks the owner for a view token, or the cloud if the owner does not answer.
+    // None if nobody answered, otherwise the remaining views if the view was granted.
+    async fn request_view_token(&self, img_id: &str, owner: SocketAddr) -> Option<Option<u32>> {
+        let owner_key = self.keyring.lock().await.get(&owner);
+        let nonce: u64 = rand::thread_rng().gen();
+        let (tx, rx) = oneshot::channel();
+        self.view_tokens.lock().await.insert(nonce, tx);
+
+        let msg = Msg {
+            sender: self.client_socket.local_addr().unwrap(),
+            receiver: owner,
+            msg_type: Type::ViewTokenRequest(String::from(img_id), nonce),
+            payload: None,
+        };
+        let serialized_msg = serde_cbor::ser::to_vec(&msg).unwrap();
+        self.client_socket
+            .send_to(&serialized_msg, owner)
+            .await
+            .unwrap();
+
+        if let Ok(Ok(token)) = timeout(Duration::from_millis(TIMEOUT_MILLIS as u64), rx).await {
+            return Some(check_view_token(token, owner_key, img_id, nonce));
+        }
+        self.view_tokens.lock().await.remove(&nonce);
+        println!(
+            "Owner {} did not answer, asking the cloud for a view token",
+            owner
+        );
+
+        let chosen_server = self
+            .send_init_request_to_cloud(Type::ClientRequest(self.req_ids.next().await))
+            .await?;
+        let msg = Msg {
+            sender: self.cloud_socket.local_addr().unwrap(),
+            receiver: chosen_server,
+            msg_type: Type::ViewTokenRequest(String::from(img_id), nonce),
+            payload: None,
+        };
+        let serialized_msg = serde_cbor::ser::to_vec(&msg).unwrap();
+        self.cloud_socket
+            .send_to(&serialized_msg, chosen_server)
+            .await
+            .unwrap();
+
+        let mut buffer = [0; 1024];
+        let sleep = sleep(Duration::from_millis(TIMEOUT_MILLIS as u64));
+        tokio::pin!(sleep);
+        loop {
+            tokio::select! {
+                _ = &mut sleep => return None,
+                recv_result = self.cloud_socket.recv_from(&mut buffer) => {
+                    match recv_result {
+                        Ok((bytes_read, _)) => match serde_cbor::de::from_slice(&buffer[..bytes_read]) {
+                            Ok(Msg {
+                                msg_type: Type::ViewToken(_, n, token),
+                                ..
+                            }) if n == nonce => return Some(check_view_token(token, owner_key, img_id, nonce)),
+                            _ => continue,
+                        },
+                        Err(e) => {
+                            eprintln!("Error getting a view token from the cloud: {}", e);
+                            continue;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // Picks up the online shares the cloud holds for us, including the views it
+    // granted while we were offline.
+    async fn sync_view_grants(&self) {
+        let chosen_server = match self
+            .send_init_request_to_cloud(Type::ClientRequest(self.req_ids.next().await))
+            .await
+        {
+            Some(chosen_server) => chosen_server,
+            None => return,
+        };
+        let msg = Msg {
+            sender: self.cloud_socket.local_addr().unwrap(),
+            receiver: chosen_server,
+            msg_type: Type::ViewGrantsQuery,
+            payload: None,
+        };
+        let serialized_msg = serde_cbor::ser::to_vec(&msg).unwrap();
+        self.cloud_socket
+            .send_to(&serialized_msg, chosen_server)
+            .await
+            .unwrap();
+
+        let mut buffer = [0; BUFFER_SIZE];
+        let sleep = sleep(Duration::from_millis(500));
+        tokio::pin!(sleep);
+        let grants = loop {
+            tokio::select! {
+                _ = &mut sleep => return,
+                recv_result = self.cloud_socket.recv_from(&mut buffer) => {
+                    match recv_result {
+                        Ok((bytes_read, _)) => match serde_cbor::de::from_slice(&buffer[..bytes_read]) {
+                            Ok(Msg {
+                                msg_type: Type::ViewGrantsQueryReply(grants),
+                                ..
+                            }) => break grants,
+                            _ => continue,
+                        },
+                        Err(_) => continue,
+                    }
+                }
+            }
+        };
+
+        info!("Online shares: {:?}", grants.keys());
+        for (img_id, (grant, remaining)) in grants {
+            if grant.value.img_id != img_id || !grant.verify(&self.identity.public_key()) {
+                continue;
+            }
+            self.online_shares.lock().await.insert(img_id.clone());
+            set_own_share(&self.own_shared_imgs, img_id, remaining).await;
+        }
+        self.state.save().await;
+    }
+
     pub async fn query_dir_of_serv(&self) -> Option<HashMap<SocketAddr, bool>> {
         if let Some(chosen_server) = self
-            .send_init_request_to_cloud(Type::ClientRequest(1))
+            .send_init_request_to_cloud(Type::ClientRequest(self.req_ids.next().await))
             .await
         {
             ClientDirOfService::query(self.cloud_socket.clone(), chosen_server).await;
@@ -805,9 +1732,12 @@ impl ClientBackend {
                             Ok(Msg {
                                 msg_type: Type::DirOfServQueryReply(r),
                                 ..
-                            }) => return Some(r),
+                            }) => {
+                                *self.dir_cache.lock().await = Some((Instant::now(), r.clone()));
+                                return Some(r);
+                            }
                             Ok(_) => continue,
-                            Err(e) => continue,
+                            Err(_) => continue,
                         }
                     }
                     Err(e) => {
@@ -830,7 +1760,7 @@ impl ClientBackend {
             let pic_path = format!("{}/{}", HIGH_RES_PICS_PATH, pic_with_ext);
             // let pic_path = pic_path.to_str().unwrap();
             let socket = self.cloud_socket.clone();
-            let id = self.get_next_req_id();
+            let id = self.req_ids.next().await;
 
             // trigger election
             if let Some(chosen_server) = self
@@ -851,20 +1781,25 @@ impl ClientBackend {
                     .await;
                 println!("Finished sending pic");
 
-                let encoded_bytes = fragment::receive_all(socket.clone()).await;
-                let encoded_image: Image = serde_cbor::de::from_slice(&encoded_bytes).unwrap();
-
-                let image_buffer: image::ImageBuffer<Rgba<u8>, Vec<u8>> =
-                    image::ImageBuffer::from_raw(
-                        encoded_image.dims.0,
-                        encoded_image.dims.1,
-                        encoded_image.data,
-                    )
+                let encoded_bytes = match fragment::receive_all(socket.clone()).await {
+                    Ok(encoded_bytes) => encoded_bytes,
+                    Err(reason) => {
+                        println!("Server refused to encrypt {}: {}", pic_with_ext, reason);
+                        continue;
+                    }
+                };
+                let encoded_parts: Vec<Image> = serde_cbor::de::from_slice(&encoded_bytes).unwrap();
+                println!("Received {} encrypted part(s)", encoded_parts.len());
+
+                let image_buffers: Vec<ImageBuffer<Rgba<u8>, Vec<u8>>> = encoded_parts
+                    .into_iter()
+                    .map(|part| image::ImageBuffer::from_raw(part.dims.0, part.dims.1, part.data))
+                    .collect::<Option<_>>()
                     .unwrap();
 
-                save_image_buffer(
-                    image_buffer.clone(),
-                    format!("{}/{}.png", ENCRYPTED_PICS_PATH, pic_without_ext),
+                save_parts(
+                    image_buffers,
+                    format!("{}/{}.png", ENCRYPTED_PICS_PATH, pic_without_ext).as_str(),
                 );
 
                 // let secret_bytes = decode_img(image_buffer).await;
@@ -876,16 +1811,144 @@ impl ClientBackend {
     }
 
     pub async fn quit(&self) {
-        ClientDirOfService::leave(self.cloud_socket.clone(), self.cloud_servers.clone()).await;
-        std_fs::write(REQ_ID_LOG_FILEPATH, self.next_req_id.to_string())
-            .expect("Failed to write req_id to req_id_log.txt");
+        ClientDirOfService::leave(
+            self.cloud_socket.clone(),
+            self.cloud_servers.lock().await.clone(),
+            self.req_ids.next().await,
+        )
+        .await;
         // complete logic for quit
     }
+}
+
+// key of an image request in `requests`, the same as the id of the share it leads to
+fn request_img_id(owner: SocketAddr, requester: SocketAddr, img_name: &str) -> String {
+    let stem = img_name.split('.').next().unwrap();
+    format!("{}&{}&{}.png", owner, requester, stem)
+}
 
-    fn get_next_req_id(&mut self) -> u32 {
-        let id = self.next_req_id;
-        self.next_req_id += 1;
-        id
+// records the remaining views of one of our shares (img_id is owner&recipient&name)
+async fn set_own_share(
+    own_shared_imgs: &Mutex<HashMap<SocketAddr, Vec<OwnShare>>>,
+    img_id: String,
+    remaining: u32,
+) {
+    let recipient = match img_id
+        .split('&')
+        .nth(1)
+        .map(|addr| addr.parse::<SocketAddr>())
+    {
+        Some(Ok(recipient)) => recipient,
+        _ => return,
+    };
+    let mut guard = own_shared_imgs.lock().await;
+    let entry = guard.entry(recipient).or_insert(Vec::new());
+    if let Some(share) = entry.iter_mut().find(|share| share.img_id == img_id) {
+        share.remaining = remaining;
+    } else {
+        entry.push(OwnShare {
+            img_id,
+            remaining,
+            last_viewed: None,
+            status: ShareStatus::Active,
+            serial: 0,
+            expiry: None,
+        });
+    }
+}
+
+// Views left under a view token, None unless the owner's pinned key signed the
+// grant for this share: a fresh view for our request `nonce` from the owner, or
+// views the cloud counted down under the owner's latest grant.
+fn check_view_token(
+    token: Option<CountedGrant>,
+    owner_key: Option<[u8; 32]>,
+    img_id: &str,
+    nonce: u64,
+) -> Option<u32> {
+    let (grant, remaining) = token?;
+    if !owner_key.is_some_and(|key| grant.verify(&key)) || grant.value.img_id != img_id {
+        println!("Ignoring view token for {}: bad signature", img_id);
+        return None;
+    }
+    let fresh = grant.value.nonce == Some(nonce) && remaining == grant.value.remaining;
+    if fresh || remaining < grant.value.remaining {
+        Some(remaining)
+    } else {
+        None
+    }
+}
+
+// Owner side of views the cloud granted under one of our grants, ignored if we
+// have signed a newer grant since.
+async fn count_cloud_views(
+    own_shared_imgs: &Mutex<HashMap<SocketAddr, Vec<OwnShare>>>,
+    grant: &OnlineGrant,
+    remaining: u32,
+) {
+    for entry in own_shared_imgs.lock().await.values_mut() {
+        if let Some(share) = entry.iter_mut().find(|share| share.img_id == grant.img_id) {
+            if share.serial == grant.serial {
+                share.remaining = share.remaining.min(remaining);
+            }
+        }
+    }
+}
+
+// the serial of the next terms signed for a share, 0 for a new one
+async fn next_serial(
+    own_shared_imgs: &Mutex<HashMap<SocketAddr, Vec<OwnShare>>>,
+    img_id: &str,
+) -> u64 {
+    for entry in own_shared_imgs.lock().await.values_mut() {
+        if let Some(share) = entry.iter_mut().find(|share| share.img_id == img_id) {
+            share.serial += 1;
+            return share.serial;
+        }
+    }
+    0
+}
+async fn set_share_expiry(
+    own_shared_imgs: &Mutex<HashMap<SocketAddr, Vec<OwnShare>>>,
+    img_id: &str,
+    expiry: Option<u64>,
+) {
+    for entry in own_shared_imgs.lock().await.values_mut() {
+        if let Some(share) = entry.iter_mut().find(|share| share.img_id == img_id) {
+            share.expiry = expiry;
+        }
+    }
+}
+async fn set_share_status(
+    own_shared_imgs: &Mutex<HashMap<SocketAddr, Vec<OwnShare>>>,
+    img_id: &str,
+    status: ShareStatus,
+) {
+    for entry in own_shared_imgs.lock().await.values_mut() {
+        if let Some(share) = entry.iter_mut().find(|share| share.img_id == img_id) {
+            // a late confirmation does not undo a share granted again since
+            if status != ShareStatus::RevokedConfirmed || share.status == ShareStatus::Revoked {
+                share.status = status;
+            }
+        }
+    }
+}
+
+// mirrors the remaining views of an online share to every cloud server
+async fn send_view_grant(
+    socket: &UdpSocket,
+    cloud_servers: &[(SocketAddr, SocketAddr)],
+    grant: &Signed<OnlineGrant>,
+) {
+    for server in cloud_servers {
+        let msg = Msg {
+            sender: socket.local_addr().unwrap(),
+            receiver: server.0,
+            msg_type: Type::ViewGrant((grant.clone(), grant.value.remaining)),
+            payload: None,
+        };
+        let serialized_msg = serde_cbor::ser::to_vec(&msg).unwrap();
+        socket.send_to(&serialized_msg, server.0).await.unwrap();
     }
 }
 
@@ -898,3 +1961,46 @@ fn file_as_image_buffer(filename: String) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
     let img = open(filename).unwrap();
     img.to_rgba8()
 }
+
+// Secrets too large for one cover are kept as several parts side by side:
+// <name>.png holds the first part (and the access limit), <name>.part<i>.png the others.
+fn part_path(path: &str, index: usize) -> String {
+    if index == 0 {
+        String::from(path)
+    } else {
+        format!("{}.part{}.png", path.trim_end_matches(".png"), index)
+    }
+}
+
+// None if a part is missing or cannot be read
+fn load_parts(path: &str) -> Option<Vec<ImageBuffer<Rgba<u8>, Vec<u8>>>> {
+    let mut parts = vec![open(path).ok()?.to_rgba8()];
+    while file_exists(part_path(path, parts.len()).as_str()) {
+        parts.push(open(part_path(path, parts.len())).ok()?.to_rgba8());
+    }
+    Some(parts)
+}
+
+// securely deletes every part of an image
+// The number of parts deleted, Err as soon as one of them could not be
+fn delete_parts(path: &str) -> std::io::Result<usize> {
+    let mut index = 0;
+    while file_exists(part_path(path, index).as_str()) {
+        secure_delete(&part_path(path, index))?;
+        index += 1;
+    }
+    Ok(index)
+}
+
+fn save_parts(parts: Vec<ImageBuffer<Rgba<u8>, Vec<u8>>>, path: &str) {
+    let mut index = 0;
+    for part in parts {
+        save_image_buffer(part, part_path(path, index));
+        index += 1;
+    }
+    // remove parts left over from an older, larger version of the image
+    while file_exists(part_path(path, index).as_str()) {
+        let _ = std_fs::remove_file(part_path(path, index));
+        index += 1;
+    }
+}
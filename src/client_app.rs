@@ -6,7 +6,8 @@
     unused_assignments
 )]
 
-use client::ClientBackend;
+use client::{ClientBackend, Request, ShareStatus};
+use manifest::AccessDenied;
 use std::time::Duration;
 use std::{collections::HashMap, env, io::Write, net::SocketAddr, sync::Arc};
 use tokio::io::AsyncBufReadExt;
@@ -14,14 +15,18 @@ use tokio::sync::Mutex;
 use tokio::time::sleep;
 
 use crate::commons::{Action, ENCRYPTED_PICS_PATH, LOW_RES_PICS_PATH};
-use crate::utils::file_exists;
+use crate::utils::{file_exists, format_time, parse_duration, parse_time};
 
 mod client;
 mod commons;
 mod dir_of_service;
 mod encryption;
 mod fragment;
+mod identity;
+mod manifest;
+mod store;
 mod utils;
+mod watermark;
 
 async fn read_input() -> String {
     let mut reader = tokio::io::BufReader::new(tokio::io::stdin());
@@ -41,7 +46,7 @@ async fn init() -> ClientBackend {
 async fn main_menu() -> State {
     println!(
         "Welcome! Choose one of the following by typing the number.\
-    \n1. Encrypt\n2. Directory of Service\n3. Edit Access\n4. View Image\n5. View Requests\n6. Quit"
+    \n1. Encrypt\n2. Directory of Service\n3. Edit Access\n4. View Image\n5. View Requests\n6. Verify Watermark\n7. Quit"
     );
     loop {
         let choice = read_input().await;
@@ -56,6 +61,8 @@ async fn main_menu() -> State {
         } else if choice == "5" {
             return State::ViewRequests;
         } else if choice == "6" {
+            return State::VerifyWatermark;
+        } else if choice == "7" {
             return State::Quit;
         }
     }
@@ -248,14 +255,20 @@ async fn edit_access(backend: Arc<Mutex<ClientBackend>>) -> State {
     let back = backend.lock().await;
     let guard = back.own_shared_imgs.lock().await;
     for (addr, imgs) in guard.iter() {
-        for img_entry in imgs {
-            let img_parts: Vec<&str> = img_entry.0.split('&').collect();
+        for share in imgs {
+            let img_parts: Vec<&str> = share.img_id.split('&').collect();
             println!(
-                "{}. {} - {} - access number: {}",
+                "{}. {} - {} - access number: {} - last viewed: {}{}",
                 shares_num + 1,
                 addr,
                 img_parts.last().unwrap(),
-                img_entry.1
+                share.remaining,
+                share.last_viewed.map_or(String::from("never"), format_time),
+                match share.status {
+                    ShareStatus::Active => "",
+                    ShareStatus::Revoked => " - revoked (awaiting confirmation)",
+                    ShareStatus::RevokedConfirmed => " - revoked (copy deleted)",
+                }
             );
             shares_num += 1;
             table.push((addr.to_string(), img_parts.last().unwrap().to_string()));
@@ -300,15 +313,20 @@ async fn view_requests(backend: Arc<Mutex<ClientBackend>>) -> State {
     println!("To go back to the main menu enter m.");
     println!("In front of you is a list requests by other clients. Choose a request by selecting its index.");
 
-    let mut v: Vec<(String, Action)> = Vec::new();
+    let mut v: Vec<(String, Request)> = Vec::new();
     let requests = backend.lock().await.requests.lock().await.clone();
 
     let reqs_num = requests.len() as u32;
 
     // let mut idx = 0;
-    for (idx, (key, action)) in requests.iter().enumerate() {
-        println!("{}. {} - {:?}", idx + 1, key, action,);
-        v.push((key.clone(), action.clone()));
+    for (idx, (key, request)) in requests.iter().enumerate() {
+        match request {
+            Request::Access(action) => println!("{}. {} - {:?}", idx + 1, key, action),
+            Request::Image(views, _) => {
+                println!("{}. {} - image with {} view(s)", idx + 1, key, views)
+            }
+        }
+        v.push((key.clone(), request.clone()));
         // idx += 1;
     }
 
@@ -326,6 +344,41 @@ async fn view_requests(backend: Arc<Mutex<ClientBackend>>) -> State {
             if idx > reqs_num || idx < 1 {
                 continue;
             } else {
+                if let Request::Image(views, recipient_key) = v[(idx - 1) as usize].1 {
+                    print!("Do you approve (y/n), or approve with fewer views (f)? ");
+                    _ = std::io::stdout().flush();
+                    let input = read_input().await;
+                    let views = if input == "m" {
+                        return State::MainMenu;
+                    } else if input == "y" {
+                        views
+                    } else if input == "f" {
+                        print!("Enter the number of views (at most {}): ", views);
+                        _ = std::io::stdout().flush();
+                        match read_input().await.parse::<u32>() {
+                            Ok(num) if num <= views => num,
+                            _ => continue,
+                        }
+                    } else if input == "n" {
+                        0
+                    } else {
+                        continue;
+                    };
+
+                    let img_id = v[(idx - 1) as usize].0.clone();
+                    let back = backend.lock().await;
+                    back.remove_request(&img_id).await;
+                    if views > 0 {
+                        back.approve_image_request(&img_id, views, recipient_key)
+                            .await;
+                    }
+                    return State::ViewRequests;
+                }
+                let action = match &v[(idx - 1) as usize].1 {
+                    Request::Access(action) => action.clone(),
+                    Request::Image(..) => continue,
+                };
+
                 print!("Do you approve (y/n)? ");
                 _ = std::io::stdout().flush();
                 let input = read_input().await;
@@ -333,32 +386,27 @@ async fn view_requests(backend: Arc<Mutex<ClientBackend>>) -> State {
                     return State::MainMenu;
                 } else if input == "n" {
                     let img_id = v[(idx - 1) as usize].0.clone();
-                    backend
-                        .lock()
-                        .await
-                        .requests
-                        .lock()
-                        .await
-                        .remove(&img_id.clone());
+                    backend.lock().await.remove_request(&img_id).await;
                     return State::ViewRequests;
                 } else if input == "y" {
                     let img_id = v[(idx - 1) as usize].0.clone();
                     let parts: Vec<&str> = img_id.split('&').collect();
                     let src_addr: SocketAddr = parts[1].parse().unwrap();
-                    ClientBackend::handle_update_access_req(
-                        v[(idx - 1) as usize].0.clone(),
-                        v[(idx - 1) as usize].1.clone(),
-                        backend.lock().await.client_socket.clone(),
-                        src_addr,
-                    )
-                    .await;
-                    backend
-                        .lock()
-                        .await
-                        .requests
+                    let terms = backend
                         .lock()
                         .await
-                        .remove(&img_id.clone());
+                        .update_own_share(img_id.clone(), &action)
+                        .await;
+                    if let Some(terms) = terms {
+                        ClientBackend::handle_update_access_req(
+                            img_id.clone(),
+                            Action::Terms(terms),
+                            backend.lock().await.client_socket.clone(),
+                            src_addr,
+                        )
+                        .await;
+                    }
+                    backend.lock().await.remove_request(&img_id).await;
                     return State::ViewRequests;
                 }
             }
@@ -408,39 +456,43 @@ async fn view_image(backend: Arc<Mutex<ClientBackend>>) -> State {
                 let src_addr = table[(idx - 1) as usize].0.parse::<SocketAddr>().unwrap();
                 let img_name = table[(idx - 1) as usize].1.clone();
 
-                if !back.view_image(img_name.clone(), src_addr).await {
-                    println!("No remaining views for this image");
-                    loop {
-                        print!("Request more views (y/n)? ");
-                        _ = std::io::stdout().flush();
-                        let input = read_input().await;
-                        if input == "m" {
-                            return State::MainMenu;
-                        } else if input == "n" {
-                            return State::ViewImage;
-                        } else if input == "y" {
-                            loop {
-                                print!("Enter the number of accesses: ");
-                                _ = std::io::stdout().flush();
-                                let input = read_input().await;
-                                if input == "m" {
-                                    return State::MainMenu;
-                                } else {
-                                    let val: u32 = match input.parse() {
-                                        Ok(num) => num,
-                                        Err(_) => continue,
-                                    };
-                                    back.request_update_access(
-                                        img_name,
-                                        Action::Increment(val),
-                                        src_addr.to_string(),
-                                    )
-                                    .await;
-                                    return State::ViewImage;
+                match back.view_image(img_name.clone(), src_addr).await {
+                    Ok(()) => {}
+                    Err(AccessDenied::NoViews) => {
+                        println!("No remaining views for this image");
+                        loop {
+                            print!("Request more views (y/n)? ");
+                            _ = std::io::stdout().flush();
+                            let input = read_input().await;
+                            if input == "m" {
+                                return State::MainMenu;
+                            } else if input == "n" {
+                                return State::ViewImage;
+                            } else if input == "y" {
+                                loop {
+                                    print!("Enter the number of accesses: ");
+                                    _ = std::io::stdout().flush();
+                                    let input = read_input().await;
+                                    if input == "m" {
+                                        return State::MainMenu;
+                                    } else {
+                                        let val: u32 = match input.parse() {
+                                            Ok(num) => num,
+                                            Err(_) => continue,
+                                        };
+                                        back.request_update_access(
+                                            img_name,
+                                            Action::Increment(val),
+                                            src_addr.to_string(),
+                                        )
+                                        .await;
+                                        return State::ViewImage;
+                                    }
                                 }
                             }
                         }
                     }
+                    Err(e) => println!("Cannot view {}: {}", img_name, e),
                 }
             }
         }
@@ -524,8 +576,28 @@ async fn view_image(backend: Arc<Mutex<ClientBackend>>) -> State {
 //     }
 // }
 
+async fn verify_watermark(backend: Arc<Mutex<ClientBackend>>) -> State {
+    println!("To go back to the main menu enter m.");
+    println!("Check who viewed an image by providing a (lossless) copy of it.");
+    loop {
+        print!("Enter a valid path: ");
+        _ = std::io::stdout().flush();
+        let input = read_input().await;
+        if input == "m" {
+            return State::MainMenu;
+        } else if !file_exists(input.as_str()) {
+            println!("This file does not exist!");
+        } else {
+            backend.lock().await.verify_watermark(input.as_str());
+        }
+    }
+}
+
 async fn get_action() -> Option<Action> {
-    println!("Select an action.\n1. Increment number of access.\n2. Decrement number of accesses.\n3. Revoke access.");
+    println!(
+        "Select an action.\n1. Increment number of access.\n2. Decrement number of accesses.\n3. Revoke access.\
+    \n4. Set expiry.\n5. Extend expiry.\n6. Shorten expiry."
+    );
     loop {
         print!("Enter your choice: ");
         _ = std::io::stdout().flush();
@@ -560,6 +632,30 @@ async fn get_action() -> Option<Action> {
             }
         } else if input == "3" {
             return Some(Action::Revoke);
+        } else if input == "4" {
+            print!("Enter expiry (YYYY-MM-DD [HH:MM] in UTC, or never): ");
+            _ = std::io::stdout().flush();
+            let input = read_input().await;
+            if input == "m" {
+                return None;
+            } else if input == "never" {
+                return Some(Action::SetExpiry(None));
+            } else if let Some(expiry) = parse_time(input.as_str()) {
+                return Some(Action::SetExpiry(Some(expiry)));
+            }
+        } else if input == "5" || input == "6" {
+            print!("Enter duration (e.g. 90m, 12h, 7d): ");
+            _ = std::io::stdout().flush();
+            let duration = read_input().await;
+            if duration == "m" {
+                return None;
+            } else if let Some(secs) = parse_duration(duration.as_str()) {
+                if input == "5" {
+                    return Some(Action::ExtendExpiry(secs));
+                } else {
+                    return Some(Action::ShortenExpiry(secs));
+                }
+            }
         }
     }
 }
@@ -574,6 +670,7 @@ enum State {
     ViewImage,
     EditAccess,
     ViewRequests,
+    VerifyWatermark,
     Quit,
 }
 
@@ -604,6 +701,9 @@ async fn main() {
             State::ViewRequests => {
                 state = view_requests(backend.clone()).await;
             }
+            State::VerifyWatermark => {
+                state = verify_watermark(backend.clone()).await;
+            }
             State::Quit => {
                 quit(backend.clone()).await;
                 break;
@@ -11,11 +11,15 @@ pub const SERVICE_PORT: usize = 8080;
 pub const ELECTION_PORT: usize = 8081;
 pub const SERVICE_SENDBACK_PORT: usize = 8082;
 pub const SERVERS_FILEPATH: &str = "./servers.txt";
-pub const REQ_ID_LOG_FILEPATH: &str = "./req_id_log.txt";
+pub const REQ_ID_LOG_FILEPATH: &str = "./req_id_log.txt"; // shared by all clients, before ids were kept per client
+pub const EMBEDDING_KEY_FILEPATH: &str = "./embedding_key.txt";
+pub const COVER_IMAGES_PATH: &str = "./default_images";
 pub const PICS_ROOT_PATH: &str = "./pics";
 pub const HIGH_RES_PICS_PATH: &str = "./pics/high";
 pub const LOW_RES_PICS_PATH: &str = "./pics/low";
 pub const ENCRYPTED_PICS_PATH: &str = "./pics/encrypted";
+pub const REPLICATED_LOG_FILEPATH: &str = "./replog"; // one file per server, by election address
+pub const SERVER_KEYRING_FILEPATH: &str = "./keyring"; // one file per server, by election address
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Msg {
@@ -28,31 +32,184 @@ pub struct Msg {
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub enum Type {
     ClientRequest(u32),
+    ClientRequestForward(SocketAddr, u32), // lease mode: to the leader, a client's request it may have missed
     ElectionRequest(f32),
     OKMsg(f32),
+    ElectionAck, // from a lower priority server, counts towards the quorum
     CoordinatorBrdCast(String),
+    LeaderLease(f32), // lease mode: the leader renews its lease, with its priority
+    LoadReport(f32),  // lease mode: a server's priority, in reply to a renewal
+    Heartbeat,
+    QuorumProbe(u64), // to every peer before a state change, answered by QuorumAck with the nonce
+    QuorumAck(u64),
+    InjectFault(Signed<FaultOrder>), // admin: a fault to inject
     Ack(String, u32),
     Fragment(Fragment),
-    Fail(u32),
     DirOfServQuery,
     DirOfServQueryReply(HashMap<SocketAddr, bool>),
     ClientDirOfServQueryPending,
-    ClientDirOfServQueryPendingReply(Option<HashMap<String, Action>>),
-    ServerDirOfServQueryPending,
-    ServerDirOfServQueryPendingReply(HashMap<SocketAddr, HashMap<String, Action>>),
+    ClientDirOfServQueryPendingReply(Option<HashMap<String, Vec<(String, Action)>>>), // img_id -> (update id, update)
+    LogVoteRequest(u64, usize, u64), // term, index and term of the last entry
+    LogVote(u64, bool),              // term, granted
+    LogAppend(u64, usize, u64, Vec<LogEntry>, usize), // term, previous index and term, entries, commit index
+    LogAppendReply(u64, bool, usize),                 // term, success, last index the follower has
+    LogForward(Option<String>, Command), // to the log leader, a command to append and its client request
+    DirStateQuery,                       // gossip mode: asks for a peer's state
+    DirState(DirState),                  // gossip mode: a peer's state, or part of it, to merge
+    ServerJoin(SocketAddr, SocketAddr, SocketAddr), // a server joining: its service, election and send addresses
+    ServerLeave(SocketAddr),                        // a server leaving, by election address
+    Membership(Vec<(SocketAddr, SocketAddr, SocketAddr)>), // to a joining server: every member
+    ServerAssignment(SocketAddr, Vec<(SocketAddr, SocketAddr)>), // to a client: the server to use, and the cloud servers
+    Refused(String), // to a client: its request will not be served, and why
     DirOfServJoin,
     DirOfServLeave,
     LowResImgReq,
     LowResImgReply(Fragment),
-    ImageRequest(String, u32),
-    SharedImage(String, Image, u32),
+    ImageRequest(String, u32, [u8; 32]),
+    SharedImage(String, Vec<Image>, u32),
     UpdateAccessRequest(String, Action),
     UpdateAccess(String, Action),
+    ViewTokenRequest(String, u64),
+    ViewToken(String, u64, Option<CountedGrant>), // img_id, nonce, the grant if a view was granted
+    ViewGrant(CountedGrant),
+    ViewGrantsQuery,
+    ViewGrantsQueryReply(HashMap<String, CountedGrant>),
 }
 
+// What the servers agree on through the replicated log, applied to the
+// directory of service in log order.
 #[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum Command {
+    Noop,                                                       // appended by a new log leader
+    Join(SocketAddr),                                           // the client's cloud socket
+    Leave(SocketAddr),                                          // the client's cloud socket
+    AccessUpdate(String, Action),                               // as sent by the client
+    PendingDelivered(SocketAddr, HashMap<String, Vec<String>>), // client -> img_id -> ids of the updates handed over
+    AddServer(SocketAddr, SocketAddr, SocketAddr), // a server joining: its service, election and send addresses
+    RemoveServer(SocketAddr),                      // a server leaving, by election address
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct LogEntry {
+    pub term: u64,
+    pub command: Command,
+    #[serde(default)]
+    pub request: Option<String>, // "{client}:{id}" of the client request it comes from
+}
+
+// server (election address) -> how many changes it made to an entry
+pub type VersionVector = HashMap<SocketAddr, u64>;
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct Versioned<T> {
+    pub value: T,
+    pub version: VersionVector,
+}
+
+// The updates left for one client about one image, each with an id telling it
+// apart (the client request it comes from), and the ids of those handed over.
+// A delivered update is dropped but its id stays, so that a replica that has
+// not seen the delivery cannot bring it back when merged. The id goes too once
+// every replica has seen the change of version (server, count) that delivered it.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct PendingLog {
+    pub actions: Vec<(String, Action)>,
+    pub delivered: HashMap<String, (SocketAddr, u64)>,
+}
+
+// A server's directory of service, as exchanged by anti-entropy (gossip mode).
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct DirState {
+    pub entries: HashMap<SocketAddr, Versioned<bool>>, // client -> online
+    pub pending_updates: HashMap<SocketAddr, HashMap<String, Versioned<PendingLog>>>, // client -> img_id -> updates
+}
+
+// Something signed by an image owner's identity key, see identity::Identity.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Signed<T> {
+    pub value: T,
+    pub signature: Vec<u8>,
+}
+
+// A fault for a server to inject, see faults::Fault::parse, signed by the admin
+// (see fault_admin). Each order is issued later than the last, so none is
+// accepted twice.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct FaultOrder {
+    pub fault: String,
+    pub issued_millis: u64, // unix time
+}
+
+// An owner's current terms for one share (img_id is owner&recipient&name). The
+// owner signs them again on every change with a higher serial, so the recipient
+// can count its views down but never up nor move the expiry, and older terms
+// arriving late are ignored.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ShareTerms {
+    pub img_id: String,
+    pub views: u32,
+    pub expiry: Option<u64>, // unix time (secs), None never expires
+    pub revoked: bool,
+    pub serial: u64,
+}
+
+// Signed by the owner of an online share (img_id is owner&recipient&name): after
+// the view it granted for the recipient's request `nonce`, or for the views the
+// cloud may grant while the owner cannot be reached, `remaining` views are left.
+// `serial` grows with every grant for the share, so the newest one wins.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct OnlineGrant {
+    pub img_id: String,
+    pub owner_key: [u8; 32],
+    pub nonce: Option<u64>,
+    pub remaining: u32,
+    pub serial: u64,
+}
+
+// an owner's grant and the views left under it, which the cloud counts down
+pub type CountedGrant = (Signed<OnlineGrant>, u32);
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub enum Action {
     Increment(u32),
     Decrement(u32),
     Revoke,
+    SetExpiry(Option<u64>),      // unix time (secs), None never expires
+    ExtendExpiry(u64),           // secs
+    ShortenExpiry(u64),          // secs
+    Viewed(u32, u64),            // view receipt for the owner: views left, when (unix time)
+    RequestImage(u32, [u8; 32]), // image request for the owner: views, requester's identity key
+    RevokeConfirmed,             // the recipient deleted its copy of a revoked image
+    Terms(Signed<ShareTerms>),   // the owner's new terms, what the recipient applies
+}
+
+impl Action {
+    // whether this goes from the recipient to the owner of the image
+    pub fn to_owner(&self) -> bool {
+        matches!(
+            self,
+            Action::Viewed(..) | Action::RequestImage(..) | Action::RevokeConfirmed
+        )
+    }
+
+    // the number of views left after applying this action to `remaining`
+    pub fn apply_views(&self, remaining: u32) -> u32 {
+        match self {
+            Action::Increment(n) => remaining.saturating_add(*n),
+            Action::Decrement(n) => remaining.saturating_sub(*n),
+            Action::Revoke => 0,
+            _ => remaining,
+        }
+    }
+
+    // the expiry after applying this action to `expiry`, a share that never
+    // expires can only be given an expiry by SetExpiry
+    pub fn apply_expiry(&self, expiry: Option<u64>) -> Option<u64> {
+        match self {
+            Action::SetExpiry(new_expiry) => *new_expiry,
+            Action::ExtendExpiry(secs) => expiry.map(|expiry| expiry.saturating_add(*secs)),
+            Action::ShortenExpiry(secs) => expiry.map(|expiry| expiry.saturating_sub(*secs)),
+            _ => expiry,
+        }
+    }
 }
@@ -0,0 +1,146 @@
+use image::{DynamicImage, ImageBuffer, Rgba};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+
+// Natural looking covers generated on the fly, so encoded images neither come
+// from a small fixed set nor reveal the secret size through a size bucket.
+#[derive(Clone, Copy, Debug)]
+pub enum CoverStyle {
+    Gradient,
+    Noise,
+    Fractal,
+}
+
+// Generates a cover with at least `capacity` pixels (as close to square as
+// possible). The same seed always gives the same cover.
+pub async fn generate_cover(seed: u64, capacity: usize) -> DynamicImage {
+    let (send, receive) = tokio::sync::oneshot::channel();
+    rayon::spawn(move || {
+        let _ = send.send(generate(seed, capacity));
+    });
+    receive.await.expect("Rayon Panicked [cover generation]")
+}
+
+pub fn generate(seed: u64, capacity: usize) -> DynamicImage {
+    let width = ((capacity.max(1) as f64).sqrt().ceil() as u32).max(1);
+    let height = (capacity.max(1) as u32).div_ceil(width);
+
+    let mut rng = ChaCha8Rng::seed_from_u64(seed);
+    let style = match rng.gen_range(0..3) {
+        0 => CoverStyle::Gradient,
+        1 => CoverStyle::Noise,
+        _ => CoverStyle::Fractal,
+    };
+    let palette = [
+        random_color(&mut rng),
+        random_color(&mut rng),
+        random_color(&mut rng),
+    ];
+    println!(
+        "Generating {:?} cover {}x{} (seed {})",
+        style, width, height, seed
+    );
+
+    let img: ImageBuffer<Rgba<u8>, Vec<u8>> = match style {
+        CoverStyle::Gradient => {
+            let angle: f32 = rng.gen_range(0.0..std::f32::consts::TAU);
+            let (dx, dy) = (angle.cos(), angle.sin());
+            let grain = ValueNoise::new(&mut rng);
+            ImageBuffer::from_fn(width, height, |x, y| {
+                let (u, v) = (x as f32 / width as f32, y as f32 / height as f32);
+                let t = ((u - 0.5) * dx + (v - 0.5) * dy + 0.5).clamp(0.0, 1.0);
+                let t = t + 0.05 * (grain.sample(x as f32 / 4.0, y as f32 / 4.0) - 0.5);
+                shade(&palette, t)
+            })
+        }
+        CoverStyle::Noise => {
+            let noise = ValueNoise::new(&mut rng);
+            let scale: f32 = rng.gen_range(32.0..128.0);
+            ImageBuffer::from_fn(width, height, |x, y| {
+                shade(&palette, noise.fbm(x as f32 / scale, y as f32 / scale, 5))
+            })
+        }
+        CoverStyle::Fractal => {
+            // Julia sets with c close to the boundary of the Mandelbrot set look organic
+            let angle: f32 = rng.gen_range(0.0..std::f32::consts::TAU);
+            let radius: f32 = rng.gen_range(0.7..0.8);
+            let (cr, ci) = (radius * angle.cos(), radius * angle.sin());
+            let zoom: f32 = rng.gen_range(1.2..2.0);
+            let max_iter = 64;
+            ImageBuffer::from_fn(width, height, |x, y| {
+                let mut zr = (x as f32 / width as f32 - 0.5) * 2.0 * zoom;
+                let mut zi = (y as f32 / height as f32 - 0.5) * 2.0 * zoom;
+                let mut iter = 0;
+                while iter < max_iter && zr * zr + zi * zi < 4.0 {
+                    let tmp = zr * zr - zi * zi + cr;
+                    zi = 2.0 * zr * zi + ci;
+                    zr = tmp;
+                    iter += 1;
+                }
+                shade(&palette, (iter as f32 / max_iter as f32).sqrt())
+            })
+        }
+    };
+    DynamicImage::ImageRgba8(img)
+}
+
+fn random_color(rng: &mut ChaCha8Rng) -> [f32; 3] {
+    [rng.gen(), rng.gen(), rng.gen()]
+}
+
+// maps t in [0, 1] onto a smooth three color palette
+fn shade(palette: &[[f32; 3]; 3], t: f32) -> Rgba<u8> {
+    let t = t.clamp(0.0, 1.0) * 2.0;
+    let (from, to, t) = if t < 1.0 {
+        (palette[0], palette[1], t)
+    } else {
+        (palette[1], palette[2], t - 1.0)
+    };
+    let channel = |i: usize| ((from[i] + (to[i] - from[i]) * t) * 255.0) as u8;
+    Rgba([channel(0), channel(1), channel(2), 255])
+}
+
+// Lattice value noise, smooth enough to pass for photographic texture.
+struct ValueNoise {
+    lattice: Vec<f32>,
+}
+
+const LATTICE_SIZE: usize = 256;
+
+impl ValueNoise {
+    fn new(rng: &mut ChaCha8Rng) -> ValueNoise {
+        ValueNoise {
+            lattice: (0..LATTICE_SIZE * LATTICE_SIZE)
+                .map(|_| rng.gen())
+                .collect(),
+        }
+    }
+
+    fn at(&self, x: i64, y: i64) -> f32 {
+        let x = x.rem_euclid(LATTICE_SIZE as i64) as usize;
+        let y = y.rem_euclid(LATTICE_SIZE as i64) as usize;
+        self.lattice[y * LATTICE_SIZE + x]
+    }
+
+    fn sample(&self, x: f32, y: f32) -> f32 {
+        let (x0, y0) = (x.floor(), y.floor());
+        let smooth = |t: f32| t * t * (3.0 - 2.0 * t);
+        let (tx, ty) = (smooth(x - x0), smooth(y - y0));
+        let (x0, y0) = (x0 as i64, y0 as i64);
+        let top = self.at(x0, y0) + (self.at(x0 + 1, y0) - self.at(x0, y0)) * tx;
+        let bottom = self.at(x0, y0 + 1) + (self.at(x0 + 1, y0 + 1) - self.at(x0, y0 + 1)) * tx;
+        top + (bottom - top) * ty
+    }
+
+    // fractional brownian motion: octaves of noise at doubling frequencies
+    fn fbm(&self, x: f32, y: f32, octaves: u32) -> f32 {
+        let (mut sum, mut amplitude, mut frequency, mut norm) = (0.0, 0.5, 1.0, 0.0);
+        for _ in 0..octaves {
+            sum += amplitude * self.sample(x * frequency, y * frequency);
+            norm += amplitude;
+            amplitude *= 0.5;
+            frequency *= 2.0;
+        }
+        sum / norm
+    }
+}
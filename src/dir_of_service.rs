@@ -1,6 +1,13 @@
-use std::{collections::HashMap, net::SocketAddr, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    sync::Arc,
+};
 
-use crate::commons::{self, Action};
+use crate::commons::{
+    self, Action, Command, CountedGrant, DirState, PendingLog, Signed, VersionVector, Versioned,
+};
+use commons::OnlineGrant;
 use commons::{Msg, Type};
 use tokio::{net::UdpSocket, sync::Mutex};
 
@@ -45,14 +52,18 @@ impl ClientDirOfService {
         self.entries = d;
     }
 
-    // subscribe
-    pub async fn join(socket: Arc<UdpSocket>, servers: Vec<(SocketAddr, SocketAddr)>) {
+    // subscribe, `request` is the id of the request (one for all the servers)
+    pub async fn join(
+        socket: Arc<UdpSocket>,
+        servers: Vec<(SocketAddr, SocketAddr)>,
+        request: u32,
+    ) {
         for server in servers {
             let msg = Msg {
                 sender: socket.local_addr().unwrap(),
                 receiver: server.0,
                 msg_type: Type::DirOfServJoin,
-                payload: None,
+                payload: Some(request.to_string()),
             };
             let serialized_msg = serde_cbor::ser::to_vec(&msg).unwrap();
             socket.send_to(&serialized_msg, server.0).await.unwrap();
@@ -60,13 +71,17 @@ impl ClientDirOfService {
     }
 
     // unsubscribe
-    pub async fn leave(socket: Arc<UdpSocket>, servers: Vec<(SocketAddr, SocketAddr)>) {
+    pub async fn leave(
+        socket: Arc<UdpSocket>,
+        servers: Vec<(SocketAddr, SocketAddr)>,
+        request: u32,
+    ) {
         for server in servers {
             let msg = Msg {
                 sender: socket.local_addr().unwrap(),
                 receiver: server.0,
                 msg_type: Type::DirOfServLeave,
-                payload: None,
+                payload: Some(request.to_string()),
             };
             let serialized_msg = serde_cbor::ser::to_vec(&msg).unwrap();
             socket.send_to(&serialized_msg, server.0).await.unwrap();
@@ -74,148 +89,448 @@ impl ClientDirOfService {
     }
 }
 
+type PendingUpdates = HashMap<SocketAddr, HashMap<String, Versioned<PendingLog>>>;
+
+// updates and delivered ids kept per client and image, so that one's pending log
+// always fits in a part of the state sent by anti-entropy
+const MAX_PENDING_UPDATES: usize = 32;
+
+// the owner, recipient and image name of an img_id (owner&recipient&name)
+pub fn parse_img_id(img_id: &str) -> Option<(SocketAddr, SocketAddr, &str)> {
+    let mut parts = img_id.splitn(3, '&');
+    let owner = parts.next()?.parse().ok()?;
+    let recipient = parts.next()?.parse().ok()?;
+    let img_name = parts.next().filter(|name| !name.is_empty())?;
+    Some((owner, recipient, img_name))
+}
+
+// Every entry carries a version vector, bumped in our own slot on each change,
+// so that in gossip mode replicas can be merged instead of overwritten.
 #[derive(Debug)]
 pub struct ServerDirOfService {
-    entries: HashMap<SocketAddr, bool>,
-    pending_updates: Mutex<HashMap<SocketAddr, HashMap<String, Action>>>,
+    server: SocketAddr, // our election address, our slot in the version vectors
+    entries: HashMap<SocketAddr, Versioned<bool>>,
+    pending_updates: Mutex<PendingUpdates>, // applied in order
+    view_grants: Mutex<HashMap<String, CountedGrant>>, // img_id -> owner's grant (online views mode)
+    seen: HashMap<(SocketAddr, String), HashMap<SocketAddr, VersionVector>>, // (client, img_id) -> peer -> version it sent us
 }
 
 impl ServerDirOfService {
-    pub fn new() -> ServerDirOfService {
+    pub fn new(server: SocketAddr) -> ServerDirOfService {
         ServerDirOfService {
+            server,
             entries: HashMap::new(),
             pending_updates: Mutex::new(HashMap::new()),
+            view_grants: Mutex::new(HashMap::new()),
+            seen: HashMap::new(),
         }
     }
 
-    // update own dir of service after recovery from failure
-    pub async fn update(&mut self, d: HashMap<SocketAddr, bool>) {
-        self.entries = d;
-        println!("{:?}", self.entries);
+    pub async fn state(&self) -> DirState {
+        DirState {
+            entries: self.entries.clone(),
+            pending_updates: self.pending_updates.lock().await.clone(),
+        }
     }
 
-    pub async fn handle_access_update_req(&mut self, img_id: String, action: Action) {
+    // Merges the state of a peer (`from`, its election address) into ours, entry
+    // by entry. The result does not depend on the order merges happen in, so
+    // replicas that have seen the same states agree, whatever each of them saw
+    // first.
+    pub async fn merge(&mut self, from: SocketAddr, other: DirState) {
+        for (client, entry) in other.entries {
+            let merged = match self.entries.remove(&client) {
+                Some(own) => merge_entry(own, entry),
+                None => entry,
+            };
+            self.entries.insert(client, merged);
+        }
+        let mut guard = self.pending_updates.lock().await;
+        for (client, imgs) in other.pending_updates {
+            let client_level = guard.entry(client).or_default();
+            for (img_id, pending) in imgs {
+                self.seen
+                    .entry((client, img_id.clone()))
+                    .or_default()
+                    .insert(from, pending.version.clone());
+                let merged = match client_level.remove(&img_id) {
+                    Some(own) => merge_pending(own, pending),
+                    None => pending,
+                };
+                client_level.insert(img_id, merged);
+            }
+        }
+    }
+
+    // A command committed in the replicated log, every server applies the same
+    // ones in the same order (in gossip mode, a client's update as it comes).
+    // `id` tells an update apart from the others, the same update reaching a
+    // server twice is only kept once; without one a fresh id is made up.
+    // Err if it cannot be applied, which then changes nothing.
+    pub async fn apply(&mut self, command: Command, id: Option<String>) -> Result<(), String> {
+        match command {
+            // membership, see ReplicatedLog::config
+            Command::Noop | Command::AddServer(..) | Command::RemoveServer(_) => {}
+            Command::Join(src_addr) => self.client_join(src_addr).await,
+            Command::Leave(src_addr) => self.client_leave(src_addr).await,
+            Command::AccessUpdate(img_id, action) => {
+                let id =
+                    id.unwrap_or_else(|| format!("{}#{:016x}", self.server, rand::random::<u64>()));
+                return self.handle_access_update_req(img_id, action, id).await;
+            }
+            Command::PendingDelivered(client, delivered) => {
+                self.remove_delivered(client, delivered).await
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn handle_access_update_req(
+        &mut self,
+        img_id: String,
+        action: Action,
+        id: String,
+    ) -> Result<(), String> {
         println!("Handling {} {:?}", img_id, action);
-        let img_id_parts: Vec<&str> = img_id.split('&').collect();
-        let target_addr = img_id_parts[1].parse::<SocketAddr>().unwrap();
-        let src_addr = img_id_parts[0].parse::<SocketAddr>().unwrap().to_string();
+        let (owner, recipient, img_name) =
+            parse_img_id(&img_id).ok_or(format!("malformed image id {}", img_id))?;
+        // receipts and requests go the other way, from the recipient to the owner
+        let (target_addr, src_addr) = if action.to_owner() {
+            (owner, recipient)
+        } else {
+            (recipient, owner)
+        };
         let mut guard = self.pending_updates.lock().await;
         let target_addr_level = guard.entry(target_addr).or_insert(HashMap::new());
-        let img_id = src_addr + "&" + img_id_parts[2];
-        target_addr_level.insert(img_id, action);
+        let img_id = format!("{}&{}", src_addr, img_name);
+        let pending = target_addr_level.entry(img_id.clone()).or_default();
+        if pending.value.has(&id) {
+            return Ok(());
+        }
+        if pending.value.actions.len() + pending.value.delivered.len() >= MAX_PENDING_UPDATES {
+            return Err(format!("too many updates pending for {}", img_id));
+        }
+        pending.value.actions.push((id, action));
+        bump(&mut pending.version, self.server);
         drop(guard);
 
         println!("{:?}", self.pending_updates.lock().await);
+        Ok(())
     }
 
-    // server wants updated Dir of service from peer servers
-    pub async fn query(socket: Arc<UdpSocket>, peers: Vec<(SocketAddr, SocketAddr, SocketAddr)>) {
-        for server in &peers {
-            let msg = Msg {
-                sender: socket.local_addr().unwrap(),
-                receiver: server.1,
-                msg_type: Type::DirOfServQuery,
-                payload: None,
-            };
-            // let serialized_msg = serde_json::to_string(&msg).unwrap();
-            let serialized_msg = serde_cbor::ser::to_vec(&msg).unwrap();
-            socket.send_to(&serialized_msg, server.1).await.unwrap();
+    // owner (or a peer server) reports the remaining views of an online share. The
+    // newest grant is kept with the fewest views left under it, so grants forwarded
+    // between servers in any order agree. The signature is checked by the caller.
+    pub async fn set_view_grant(&self, grant: Signed<OnlineGrant>, remaining: u32) {
+        println!("View grant {}: {}", grant.value.img_id, remaining);
+        let remaining = remaining.min(grant.value.remaining);
+        let mut guard = self.view_grants.lock().await;
+        match guard.get_mut(&grant.value.img_id) {
+            Some((kept, left)) if kept.value.serial == grant.value.serial => {
+                *left = (*left).min(remaining);
+            }
+            Some((kept, _)) if kept.value.serial > grant.value.serial => {}
+            _ => {
+                guard.insert(grant.value.img_id.clone(), (grant, remaining));
+            }
         }
     }
 
-    // server wants updated pending requests from peer servers
-    pub async fn query_pending(
-        socket: Arc<UdpSocket>,
-        peers: Vec<(SocketAddr, SocketAddr, SocketAddr)>,
-    ) {
-        for server in &peers {
-            let msg = Msg {
-                sender: socket.local_addr().unwrap(),
-                receiver: server.1,
-                msg_type: Type::ServerDirOfServQueryPending,
-                payload: None,
-            };
-            let serialized_msg = serde_cbor::ser::to_vec(&msg).unwrap();
-            socket.send_to(&serialized_msg, server.1).await.unwrap();
+    // consume one view on behalf of an offline owner, the owner's grant and the
+    // remaining views if granted
+    pub async fn issue_view_token(&self, img_id: &str) -> Option<CountedGrant> {
+        let mut guard = self.view_grants.lock().await;
+        match guard.get_mut(img_id) {
+            Some((grant, remaining)) if *remaining > 0 => {
+                *remaining -= 1;
+                Some((grant.clone(), *remaining))
+            }
+            _ => None,
         }
     }
 
-    // update own pending updates after recovery from failure
-    pub async fn update_pending_requests(
-        &mut self,
-        d: HashMap<SocketAddr, HashMap<String, Action>>,
-    ) {
-        self.pending_updates = Mutex::new(d);
-        println!("{:?}", self.pending_updates);
-    }
-
-    // send dir of service back to the one sent a query
-    pub async fn query_reply(&self, socket: Arc<UdpSocket>, src_addr: SocketAddr) {
-        let sender = socket.local_addr().unwrap();
+    // send the online shares of the owner that sent a query
+    pub async fn view_grants_reply(&self, socket: Arc<UdpSocket>, src_addr: SocketAddr) {
+        let owner = SocketAddr::new(src_addr.ip(), src_addr.port() + 1);
+        let prefix = format!("{}&", owner);
+        let grants: HashMap<String, CountedGrant> = self
+            .view_grants
+            .lock()
+            .await
+            .iter()
+            .filter(|(img_id, _)| img_id.starts_with(&prefix))
+            .map(|(img_id, grant)| (img_id.clone(), grant.clone()))
+            .collect();
         let msg = Msg {
-            sender,
+            sender: socket.local_addr().unwrap(),
             receiver: src_addr,
-            msg_type: Type::DirOfServQueryReply(self.entries.clone()),
+            msg_type: Type::ViewGrantsQueryReply(grants),
             payload: None,
         };
         let serialized_msg = serde_cbor::ser::to_vec(&msg).unwrap();
         socket.send_to(&serialized_msg, src_addr).await.unwrap();
     }
 
-    // send pending requests to the server that sent a query
-    pub async fn client_query_pending_reply(&self, socket: Arc<UdpSocket>, src_addr: SocketAddr) {
-        let client_socket = SocketAddr::new(src_addr.ip(), src_addr.port() + 1);
+    // send dir of service back to the one sent a query
+    pub async fn query_reply(&self, socket: Arc<UdpSocket>, src_addr: SocketAddr) {
         let sender = socket.local_addr().unwrap();
         let msg = Msg {
             sender,
             receiver: src_addr,
-            msg_type: Type::ClientDirOfServQueryPendingReply(
-                self.pending_updates
-                    .lock()
-                    .await
-                    .get(&client_socket)
-                    .cloned(),
+            msg_type: Type::DirOfServQueryReply(
+                self.entries
+                    .iter()
+                    .map(|(client, entry)| (*client, entry.value))
+                    .collect(),
             ),
             payload: None,
         };
-
-        self.pending_updates.lock().await.remove(&client_socket);
         let serialized_msg = serde_cbor::ser::to_vec(&msg).unwrap();
         socket.send_to(&serialized_msg, src_addr).await.unwrap();
     }
 
-    // send pending requests to the server that sent a query
-    pub async fn server_query_pending_reply(&self, socket: Arc<UdpSocket>, src_addr: SocketAddr) {
+    // Send pending requests to the client that sent a query. They are only marked
+    // delivered once the returned command (client, img_id -> ids of the updates
+    // sent) is applied, so every server drops the same ones and updates arriving
+    // meanwhile stay.
+    pub async fn client_query_pending_reply(
+        &self,
+        socket: Arc<UdpSocket>,
+        src_addr: SocketAddr,
+    ) -> Option<Command> {
+        let client_socket = SocketAddr::new(src_addr.ip(), src_addr.port() + 1);
+        let pending: HashMap<String, Vec<(String, Action)>> =
+            match self.pending_updates.lock().await.get(&client_socket) {
+                Some(imgs) => imgs
+                    .iter()
+                    .filter(|(_, pending)| !pending.value.actions.is_empty())
+                    .map(|(img_id, pending)| (img_id.clone(), pending.value.actions.clone()))
+                    .collect(),
+                None => HashMap::new(),
+            };
+        // with their ids, the client skips those it has applied already
         let sender = socket.local_addr().unwrap();
         let msg = Msg {
             sender,
             receiver: src_addr,
-            msg_type: Type::ServerDirOfServQueryPendingReply(
-                self.pending_updates.lock().await.clone(),
+            msg_type: Type::ClientDirOfServQueryPendingReply(
+                Some(pending.clone()).filter(|imgs| !imgs.is_empty()),
             ),
             payload: None,
         };
         let serialized_msg = serde_cbor::ser::to_vec(&msg).unwrap();
         socket.send_to(&serialized_msg, src_addr).await.unwrap();
+
+        if pending.is_empty() {
+            return None;
+        }
+        let delivered = pending
+            .into_iter()
+            .map(|(img_id, actions)| (img_id, actions.into_iter().map(|(id, _)| id).collect()))
+            .collect();
+        Some(Command::PendingDelivered(client_socket, delivered))
+    }
+
+    // mark the pending updates a client has been sent as delivered, they are
+    // dropped and only their ids kept
+    async fn remove_delivered(&self, client: SocketAddr, delivered: HashMap<String, Vec<String>>) {
+        let mut guard = self.pending_updates.lock().await;
+        if let Some(client_level) = guard.get_mut(&client) {
+            for (img_id, ids) in delivered {
+                if let Some(pending) = client_level.get_mut(&img_id) {
+                    bump(&mut pending.version, self.server);
+                    let dot = (self.server, pending.version[&self.server]);
+                    for id in ids {
+                        pending.value.delivered.entry(id).or_insert(dot);
+                    }
+                    pending.value.compact();
+                }
+            }
+        }
+    }
+
+    // Forgets the ids of the updates that every replica has seen delivered, none
+    // can bring them back any more. `replicas` are the other servers, none in
+    // log mode, where no state is merged.
+    pub async fn collect_delivered(&self, replicas: &[SocketAddr]) {
+        let mut guard = self.pending_updates.lock().await;
+        for (client, imgs) in guard.iter_mut() {
+            for (img_id, pending) in imgs.iter_mut() {
+                let seen = self.seen.get(&(*client, img_id.clone()));
+                pending.value.delivered.retain(|_, (server, count)| {
+                    !replicas.iter().all(|replica| {
+                        seen.and_then(|seen| seen.get(replica))
+                            .and_then(|version| version.get(server))
+                            .is_some_and(|seen_count| seen_count >= count)
+                    })
+                });
+            }
+        }
     }
 
     // client wants to subscribe
     pub async fn client_join(&mut self, src_addr: SocketAddr) {
         let addr = SocketAddr::new(src_addr.ip(), src_addr.port() + 1);
-        self.entries
-            .entry(addr)
-            .and_modify(|value| *value = true)
-            .or_insert(true);
+        let entry = self.entries.entry(addr).or_default();
+        entry.value = true;
+        bump(&mut entry.version, self.server);
         println!("{:?}", self.entries);
     }
 
     // client wants to unsubscribe
     pub async fn client_leave(&mut self, src_addr: SocketAddr) {
         let addr = SocketAddr::new(src_addr.ip(), src_addr.port() + 1);
-        self.entries
-            .entry(addr)
-            .and_modify(|value| *value = false)
-            .or_insert(false);
+        let entry = self.entries.entry(addr).or_default();
+        entry.value = false;
+        bump(&mut entry.version, self.server);
         println!("{:?}", self.entries);
     }
 }
+
+impl PendingLog {
+    // the updates not handed over yet, in order
+    pub fn pending(&self) -> Vec<Action> {
+        self.actions
+            .iter()
+            .map(|(_, action)| action.clone())
+            .collect()
+    }
+
+    // whether the update with this id was already received
+    fn has(&self, id: &str) -> bool {
+        self.delivered.contains_key(id) || self.actions.iter().any(|(other, _)| other == id)
+    }
+
+    // drops the updates handed over
+    fn compact(&mut self) {
+        let delivered = &self.delivered;
+        self.actions.retain(|(id, _)| !delivered.contains_key(id));
+    }
+}
+
+// Splits a directory state into parts whose encoding fits in `max_bytes`, to be
+// sent one datagram each. Merging every part gives the same as merging the
+// whole. An entry too big on its own (see MAX_PENDING_UPDATES) is left out,
+// the receiver could not read it anyway.
+pub fn split_state(state: DirState, max_bytes: usize) -> Vec<DirState> {
+    let mut parts = vec![DirState::default()];
+    let mut used = 0;
+    let mut room_for = |parts: &mut Vec<DirState>, len: usize| {
+        if used + len > max_bytes && used > 0 {
+            parts.push(DirState::default());
+            used = 0;
+        }
+        used += len;
+    };
+    for (client, entry) in state.entries {
+        room_for(&mut parts, encoded_len(&(&client, &entry)));
+        parts.last_mut().unwrap().entries.insert(client, entry);
+    }
+    for (client, imgs) in state.pending_updates {
+        for (img_id, pending) in imgs {
+            let len = encoded_len(&(&client, &img_id, &pending));
+            if len > max_bytes {
+                println!(
+                    "Not sending the updates for {} {}: {} bytes",
+                    client, img_id, len
+                );
+                continue;
+            }
+            room_for(&mut parts, len);
+            let last = parts.last_mut().unwrap();
+            last.pending_updates
+                .entry(client)
+                .or_default()
+                .insert(img_id, pending);
+        }
+    }
+    parts
+}
+
+fn encoded_len<T: serde::Serialize>(value: &T) -> usize {
+    serde_cbor::to_vec(value).map_or(0, |bytes| bytes.len())
+}
+
+fn bump(version: &mut VersionVector, server: SocketAddr) {
+    *version.entry(server).or_insert(0) += 1;
+}
+
+// whether `a` has seen every change `b` has
+fn dominates(a: &VersionVector, b: &VersionVector) -> bool {
+    b.iter()
+        .all(|(server, count)| a.get(server).copied().unwrap_or(0) >= *count)
+}
+
+fn merge_versions(a: &VersionVector, b: &VersionVector) -> VersionVector {
+    let mut merged = a.clone();
+    for (server, count) in b {
+        let slot = merged.entry(*server).or_insert(0);
+        *slot = (*slot).max(*count);
+    }
+    merged
+}
+
+// Concurrent joins and leaves of a client: the side with more changes wins,
+// and online on a tie.
+fn merge_entry(a: Versioned<bool>, b: Versioned<bool>) -> Versioned<bool> {
+    if dominates(&a.version, &b.version) {
+        return a;
+    }
+    if dominates(&b.version, &a.version) {
+        return b;
+    }
+    let changes = |entry: &Versioned<bool>| entry.version.values().sum::<u64>();
+    let value = match changes(&a).cmp(&changes(&b)) {
+        std::cmp::Ordering::Greater => a.value,
+        std::cmp::Ordering::Less => b.value,
+        std::cmp::Ordering::Equal => a.value || b.value,
+    };
+    Versioned {
+        value,
+        version: merge_versions(&a.version, &b.version),
+    }
+}
+
+// Concurrent pending updates: an update that reached several servers is kept
+// once, one that reached only some of them is kept too, and so is every
+// delivery. A delivery can race an update it has not seen, which then stays
+// pending, never the other way round.
+fn merge_pending(a: Versioned<PendingLog>, b: Versioned<PendingLog>) -> Versioned<PendingLog> {
+    if dominates(&a.version, &b.version) {
+        return a;
+    }
+    if dominates(&b.version, &a.version) {
+        return b;
+    }
+    // both sides may have delivered an update, either delivery will do
+    let mut delivered = a.value.delivered;
+    for (id, dot) in b.value.delivered {
+        let kept = delivered.entry(id).or_insert(dot);
+        *kept = (*kept).min(dot);
+    }
+    let mut merged = PendingLog {
+        actions: union_by_id(&a.value.actions, &b.value.actions),
+        delivered,
+    };
+    merged.compact();
+    Versioned {
+        value: merged,
+        version: merge_versions(&a.version, &b.version),
+    }
+}
+
+// Every update of either side once, in the order of the side that has more of
+// them (a missed update is usually the only difference), ties broken by the
+// encoding so that both sides end up with the same order.
+fn union_by_id(a: &[(String, Action)], b: &[(String, Action)]) -> Vec<(String, Action)> {
+    let encoded = |actions: &[(String, Action)]| serde_cbor::to_vec(&actions).unwrap();
+    let (first, second) = if (a.len(), encoded(a)) >= (b.len(), encoded(b)) {
+        (a, b)
+    } else {
+        (b, a)
+    };
+    let ids: HashSet<&String> = first.iter().map(|(id, _)| id).collect();
+    let mut union = first.to_vec();
+    union.extend(second.iter().filter(|(id, _)| !ids.contains(id)).cloned());
+    union
+}
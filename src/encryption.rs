@@ -1,92 +1,436 @@
 use image::{open, DynamicImage, ImageBuffer, Rgba};
-use std::path::Path;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use rand_chacha::ChaCha20Rng;
+use rayon::prelude::*;
+use std::cmp::min;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
 
 use crate::fragment::Image;
 
-pub struct ImageLoader {
-    pub def1: Option<DynamicImage>,
-    pub def2: Option<DynamicImage>,
-    pub def3: Option<DynamicImage>,
-    pub def4: Option<DynamicImage>,
-    pub def5: Option<DynamicImage>,
-    pub def6: Option<DynamicImage>,
-}
-
-impl ImageLoader {
-    pub fn load_image(&mut self, path: &str) -> DynamicImage {
-        // Load the image if not already loaded
-        let entry = match path {
-            "default_images/def1.png" => &mut self.def1,
-            "default_images/def2.png" => &mut self.def2,
-            "default_images/def3.png" => &mut self.def3,
-            "default_images/def4.png" => &mut self.def4,
-            "default_images/def5.png" => &mut self.def5,
-            "default_images/def6.png" => &mut self.def6,
-            _ => panic!("Unsupported image path"),
+// A cover image the server can embed secrets into. One secret byte goes into
+// the alpha channel of every pixel, so the capacity is the pixel count minus
+// the pixels reserved for the access manifest.
+pub struct Cover {
+    pub path: PathBuf,
+    pub capacity: usize,
+    modified: Option<SystemTime>,
+    image: Option<DynamicImage>,
+}
+
+// Pool of cover images found in a directory, kept sorted by capacity so the
+// smallest cover that fits a secret can be picked.
+pub struct CoverPool {
+    dir: String,
+    covers: Vec<Cover>,
+}
+
+impl CoverPool {
+    pub fn new(dir: &str) -> CoverPool {
+        let mut pool = CoverPool {
+            dir: String::from(dir),
+            covers: Vec::new(),
+        };
+        pool.scan();
+        pool
+    }
+
+    // (re)index the cover directory; covers that were added, changed or removed
+    // since the last scan are picked up, already indexed ones are kept as is.
+    // This reads the directory, the server runs it periodically off the executor.
+    pub fn scan(&mut self) {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                println!("Failed to read cover directory {}: {}", self.dir, e);
+                return;
+            }
         };
 
-        if let Some(image) = entry {
-            image.clone()
-        } else {
-            let loaded_image = image::open(path).unwrap();
-            *entry = Some(loaded_image.clone());
-            loaded_image
+        let mut covers = Vec::new();
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let modified = entry.metadata().and_then(|m| m.modified()).ok();
+            if let Some(pos) = self
+                .covers
+                .iter()
+                .position(|c| c.path == path && c.modified == modified)
+            {
+                covers.push(self.covers.swap_remove(pos));
+                continue;
+            }
+            // only the header is read here, the pixels are loaded on first use
+            if let Ok((width, height)) = image::image_dimensions(&path) {
+                let capacity = payload_capacity(width, height);
+                println!(
+                    "Indexed cover {} ({}x{}) with capacity {} bytes",
+                    path.display(),
+                    width,
+                    height,
+                    capacity
+                );
+                covers.push(Cover {
+                    path,
+                    capacity,
+                    modified,
+                    image: None,
+                });
+            }
+        }
+        covers.sort_by_key(|c| c.capacity);
+        self.covers = covers;
+    }
+
+    pub fn max_capacity(&self) -> usize {
+        self.covers.last().map_or(0, |c| c.capacity)
+    }
+
+    // covers for a secret of secret_len bytes: the smallest single cover that fits,
+    // otherwise as many of the largest covers as needed plus the smallest cover
+    // fitting the remainder. Every cover also carries a part header.
+    pub fn select(&mut self, secret_len: usize) -> Option<Vec<DynamicImage>> {
+        let max_chunk = self.max_capacity().checked_sub(PART_HEADER_LEN)?;
+        if max_chunk == 0 {
+            return None;
+        }
+
+        let parts_num = secret_len.div_ceil(max_chunk);
+        let mut covers = Vec::new();
+        for part in 0..parts_num.max(1) {
+            let chunk_len = min(max_chunk, secret_len - part * max_chunk);
+            let cover = self
+                .covers
+                .iter_mut()
+                .find(|c| c.capacity >= chunk_len + PART_HEADER_LEN)?;
+            println!(
+                "Selected cover {} (capacity {}) for part {}/{} of {} bytes",
+                cover.path.display(),
+                cover.capacity,
+                part + 1,
+                parts_num.max(1),
+                chunk_len
+            );
+            if cover.image.is_none() {
+                cover.image = Some(image::open(&cover.path).ok()?);
+            }
+            covers.push(cover.image.clone()?);
+        }
+        Some(covers)
+    }
+}
+
+// Every cover starts with a header telling which part of the secret it holds:
+// part index, number of parts and number of secret bytes in this part (u32, BE).
+pub const PART_HEADER_LEN: usize = 12;
+
+#[derive(Clone, Copy, Debug)]
+pub struct PartHeader {
+    pub index: u32,
+    pub count: u32,
+    pub len: u32,
+}
+
+impl PartHeader {
+    fn to_bytes(self) -> Vec<u8> {
+        [self.index, self.count, self.len]
+            .iter()
+            .flat_map(|n| n.to_be_bytes())
+            .collect()
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<PartHeader> {
+        if bytes.len() < PART_HEADER_LEN {
+            return None;
+        }
+        let field = |i: usize| u32::from_be_bytes(bytes[4 * i..4 * i + 4].try_into().unwrap());
+        Some(PartHeader {
+            index: field(0),
+            count: field(1),
+            len: field(2),
+        })
+    }
+}
+
+// Order in which the secret bytes are laid out over the pixels of the cover.
+// Raster starts at pixel (0,0), Keyed permutes the pixel positions with a
+// ChaCha20 stream seeded by a shared key, so the payload can only be located
+// (and read back) by the holders of that key.
+#[derive(Clone, Copy, Debug)]
+pub enum PixelOrder {
+    Raster,
+    Keyed([u8; 32]),
+}
+
+impl PixelOrder {
+    // indices[i] is the (row-major) index of the pixel carrying the i-th secret byte
+    pub fn indices(&self, pixels: u32) -> Vec<u32> {
+        let mut indices: Vec<u32> = (0..pixels).collect();
+        if let PixelOrder::Keyed(key) = self {
+            let mut rng = ChaCha20Rng::from_seed(*key);
+            indices.shuffle(&mut rng);
         }
+        indices
+    }
+}
+
+// The last MANIFEST_LEN pixels of every cover never carry payload; their alpha
+// channel holds the access manifest of a shared image: a u32 (BE) length
+// followed by that many manifest bytes.
+pub const MANIFEST_LEN: usize = 1024;
+
+pub fn payload_capacity(width: u32, height: u32) -> usize {
+    ((width * height) as usize).saturating_sub(MANIFEST_LEN)
+}
+
+pub fn read_manifest_block(img: &ImageBuffer<Rgba<u8>, Vec<u8>>) -> Option<Vec<u8>> {
+    let pixels = (img.width() * img.height()) as usize;
+    if pixels < MANIFEST_LEN {
+        return None;
+    }
+    let block: Vec<u8> = img.as_raw()[4 * (pixels - MANIFEST_LEN)..]
+        .chunks_exact(4)
+        .map(|pixel| pixel[3])
+        .collect();
+    let len = u32::from_be_bytes(block[..4].try_into().unwrap()) as usize;
+    if len == 0 || len > MANIFEST_LEN - 4 {
+        return None;
     }
+    Some(block[4..4 + len].to_vec())
 }
 
+// false if the manifest does not fit in the reserved pixels
+pub fn write_manifest_block(img: &mut ImageBuffer<Rgba<u8>, Vec<u8>>, manifest: &[u8]) -> bool {
+    let pixels = (img.width() * img.height()) as usize;
+    if pixels < MANIFEST_LEN || manifest.len() > MANIFEST_LEN - 4 {
+        return false;
+    }
+    let block = (manifest.len() as u32)
+        .to_be_bytes()
+        .into_iter()
+        .chain(manifest.iter().copied())
+        .chain(std::iter::repeat(255));
+    for (pixel, byte) in (**img)[4 * (pixels - MANIFEST_LEN)..]
+        .chunks_exact_mut(4)
+        .zip(block)
+    {
+        pixel[3] = byte;
+    }
+    true
+}
+
+// Embedding and extraction work on bands of this many rows in parallel on the rayon pool
+pub const ROWS_PER_CHUNK: usize = 64;
+
+// Writes `stream` into the alpha channel of `img` following `order`.
+pub fn embed(img: &mut ImageBuffer<Rgba<u8>, Vec<u8>>, stream: &[u8], order: PixelOrder) {
+    let width = img.width() as usize;
+    let pixels = payload_capacity(img.width(), img.height());
+    let stream = &stream[..min(stream.len(), pixels)];
+
+    // slots[pixel] is the position in the stream of the byte this pixel carries
+    let slots = match order {
+        PixelOrder::Raster => None,
+        PixelOrder::Keyed(_) => {
+            let mut slots = vec![u32::MAX; (img.width() * img.height()) as usize];
+            for (slot, pixel_index) in order
+                .indices(pixels as u32)
+                .into_iter()
+                .take(stream.len())
+                .enumerate()
+            {
+                slots[pixel_index as usize] = slot as u32;
+            }
+            Some(slots)
+        }
+    };
+
+    img.par_chunks_mut(4 * width * ROWS_PER_CHUNK)
+        .enumerate()
+        .for_each(|(chunk, rows)| {
+            let first_pixel = chunk * width * ROWS_PER_CHUNK;
+            for (offset, pixel) in rows.chunks_exact_mut(4).enumerate() {
+                let slot = match &slots {
+                    None => first_pixel + offset,
+                    Some(slots) => slots[first_pixel + offset] as usize,
+                };
+                if slot < stream.len() {
+                    pixel[3] = stream[slot];
+                }
+            }
+        });
+}
+
+// Reads one part (header and secret bytes) back out of `img`, None if the header is corrupted.
+pub fn extract(
+    img: &ImageBuffer<Rgba<u8>, Vec<u8>>,
+    order: PixelOrder,
+) -> Option<(PartHeader, Vec<u8>)> {
+    let width = img.width() as usize;
+    let pixels = payload_capacity(img.width(), img.height());
+    let raw: &[u8] = img.as_raw();
+
+    let indices = match order {
+        PixelOrder::Raster => None,
+        PixelOrder::Keyed(_) => Some(order.indices(pixels as u32)),
+    };
+    let alpha = |slot: usize| match &indices {
+        None => raw[4 * slot + 3],
+        Some(indices) => raw[4 * indices[slot] as usize + 3],
+    };
+
+    if pixels < PART_HEADER_LEN {
+        return None;
+    }
+    let header_bytes: Vec<u8> = (0..PART_HEADER_LEN).map(alpha).collect();
+    let header = PartHeader::from_bytes(&header_bytes)?;
+    if PART_HEADER_LEN + header.len as usize > pixels {
+        return None;
+    }
+
+    let mut out = vec![0; header.len as usize];
+    out.par_chunks_mut(width * ROWS_PER_CHUNK)
+        .enumerate()
+        .for_each(|(chunk, bytes)| {
+            let first_slot = PART_HEADER_LEN + chunk * width * ROWS_PER_CHUNK;
+            for (offset, byte) in bytes.iter_mut().enumerate() {
+                *byte = alpha(first_slot + offset);
+            }
+        });
+    Some((header, out))
+}
+
+// Embeds the secret into the given covers (in order) and returns the cbor encoded
+// list of parts, or None if the covers cannot hold the whole secret.
 pub async fn encode_img(
     secret_bytes: Vec<u8>,
     req_id: String,
-    default_image: DynamicImage,
-) -> Vec<u8> {
+    covers: Vec<DynamicImage>,
+    order: PixelOrder,
+) -> Option<Vec<u8>> {
     let (send, receive) = tokio::sync::oneshot::channel();
     rayon::spawn(move || {
         println!(
-            "[{}] Started encryption. Image size {}",
+            "[{}] Started encryption. Image size {}, {} part(s)",
             req_id,
-            secret_bytes.len()
+            secret_bytes.len(),
+            covers.len()
         );
-        // let encoder = Encoder::new(&data, default_image);
-        // let encoded_image = encoder.encode_alpha();
 
-        let img: ImageBuffer<Rgba<u8>, Vec<u8>> = default_image.into_rgba8();
-        let (width, height) = img.dimensions();
-        let bytes = width * height;
+        // split the secret up front so the parts can be embedded in parallel
+        let count = covers.len() as u32;
+        let mut remaining = &secret_bytes[..];
+        let mut jobs = Vec::new();
+        for (index, cover) in covers.into_iter().enumerate() {
+            let img: ImageBuffer<Rgba<u8>, Vec<u8>> = cover.into_rgba8();
+            let bytes = payload_capacity(img.width(), img.height());
+            let chunk_len = min(remaining.len(), bytes.saturating_sub(PART_HEADER_LEN));
+            let (chunk, rest) = remaining.split_at(chunk_len);
+            remaining = rest;
 
-        if secret_bytes.len() > bytes as usize {
-            panic!("secret_bytes is too large for image size");
+            let header = PartHeader {
+                index: index as u32,
+                count,
+                len: chunk_len as u32,
+            };
+            jobs.push((img, [header.to_bytes(), chunk.to_vec()].concat()));
         }
 
-        let mut encoded_image = img.clone();
-
-        for (x, y, pixel) in encoded_image.enumerate_pixels_mut() {
-            let secret_bytes_index = x + (y * width);
-
-            if secret_bytes_index < secret_bytes.len() as u32 {
-                pixel[3] = secret_bytes[secret_bytes_index as usize];
-            } else {
-                // If secret bytes are exhausted, break out of the loop
-                break;
-            }
+        if !remaining.is_empty() {
+            println!(
+                "[{}] Covers are too small, {} bytes left over",
+                req_id,
+                remaining.len()
+            );
+            let _ = send.send(None);
+            return;
         }
 
-        let image = Image {
-            dims: encoded_image.dimensions(),
-            data: encoded_image.into_raw(),
-        };
-        let encoded_bytes = serde_cbor::to_vec(&image).unwrap();
-        let _ = send.send(encoded_bytes);
+        let parts: Vec<Image> = jobs
+            .into_par_iter()
+            .map(|(mut encoded_image, stream)| {
+                embed(&mut encoded_image, &stream, order);
+                Image {
+                    dims: encoded_image.dimensions(),
+                    data: encoded_image.into_raw(),
+                }
+            })
+            .collect();
+        let _ = send.send(Some(serde_cbor::to_vec(&parts).unwrap()));
     });
 
     receive.await.expect("Rayon Panicked [encrption]")
 }
 
-pub async fn decode_img(img: ImageBuffer<Rgba<u8>, Vec<u8>>) -> Vec<u8> {
-    let mut out: Vec<u8> = Vec::new();
+// Extracts every part and stitches the secret back together, None if parts are
+// missing or corrupted. Runs on the rayon pool to keep the async runtime responsive.
+pub async fn decode_parts(
+    imgs: Vec<ImageBuffer<Rgba<u8>, Vec<u8>>>,
+    order: PixelOrder,
+) -> Option<Vec<u8>> {
+    let (send, receive) = tokio::sync::oneshot::channel();
+    rayon::spawn(move || {
+        let parts: Option<Vec<(PartHeader, Vec<u8>)>> =
+            imgs.par_iter().map(|img| extract(img, order)).collect();
+        let _ = send.send(parts.and_then(join_parts));
+    });
+
+    receive.await.expect("Rayon Panicked [decryption]")
+}
+
+type Parts = Vec<ImageBuffer<Rgba<u8>, Vec<u8>>>;
+
+// Runs `f` over the secret carried by `imgs` and embeds the result back into the
+// same images. `f` must keep the length of the secret so every part keeps its header.
+pub async fn rewrite_parts<F, R>(imgs: Parts, order: PixelOrder, f: F) -> Option<(Parts, R)>
+where
+    F: FnOnce(&mut [u8]) -> R + Send + 'static,
+    R: Send + 'static,
+{
+    let (send, receive) = tokio::sync::oneshot::channel();
+    rayon::spawn(move || {
+        let _ = send.send(rewrite(imgs, order, f));
+    });
+
+    receive.await.expect("Rayon Panicked [rewrite]")
+}
+
+fn rewrite<F, R>(mut imgs: Parts, order: PixelOrder, f: F) -> Option<(Parts, R)>
+where
+    F: FnOnce(&mut [u8]) -> R,
+{
+    let parts: Vec<(PartHeader, Vec<u8>)> = imgs
+        .par_iter()
+        .map(|img| extract(img, order))
+        .collect::<Option<_>>()?;
+    let headers: Vec<PartHeader> = parts.iter().map(|(header, _)| *header).collect();
+    let mut secret = join_parts(parts)?;
+    let result = f(&mut secret);
+
+    // parts are joined by index, so a part starts after all the parts before it
+    imgs.par_iter_mut()
+        .zip(headers.par_iter())
+        .for_each(|(img, header)| {
+            let offset: usize = headers
+                .iter()
+                .filter(|other| other.index < header.index)
+                .map(|other| other.len as usize)
+                .sum();
+            let chunk = &secret[offset..offset + header.len as usize];
+            embed(img, &[header.to_bytes(), chunk.to_vec()].concat(), order);
+        });
+    Some((imgs, result))
+}
 
-    for (_, _, pixel) in img.enumerate_pixels() {
-        out.push(pixel[3]);
+fn join_parts(mut parts: Vec<(PartHeader, Vec<u8>)>) -> Option<Vec<u8>> {
+    parts.sort_by_key(|(header, _)| header.index);
+    let complete = parts
+        .iter()
+        .enumerate()
+        .all(|(i, (header, _))| header.index == i as u32 && header.count == parts.len() as u32);
+    if !complete {
+        return None;
     }
-    out
+    Some(parts.into_iter().flat_map(|(_, chunk)| chunk).collect())
 }
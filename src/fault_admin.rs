@@ -0,0 +1,71 @@
+#![allow(
+    dead_code,
+    unused_variables,
+    unused_imports,
+    clippy::redundant_allocation,
+    unused_assignments
+)]
+
+use std::env;
+use std::net::SocketAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::net::UdpSocket;
+
+mod commons;
+mod cover_gen;
+mod encryption;
+mod fragment;
+mod identity;
+mod utils;
+use commons::{FaultOrder, Msg, Signed, Type};
+use identity::Identity;
+
+// the admin's identity, whose public key servers are given as FAULT_ADMIN_KEY
+const ADMIN_KEY_FILEPATH: &str = "./fault_admin.key";
+
+// Injects a fault into a running server, e.g.
+//   fault_admin 127.0.0.1:8081 drop election 30
+//   fault_admin 127.0.0.1:8081 partition 127.0.0.1:8084
+// The server is addressed by its election address, the fault syntax is the
+// one of FAULT_SCHEDULE files (see faults.rs). Servers only take faults signed
+// by the admin key they were started with: `fault_admin key` prints it.
+#[tokio::main]
+async fn main() {
+    let args: Vec<_> = env::args().collect();
+    let identity = Identity::load_or_create(ADMIN_KEY_FILEPATH);
+    if args.len() == 2 && args[1] == "key" {
+        let key: String = identity
+            .public_key()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        println!("{}", key);
+        return;
+    }
+    if args.len() < 3 {
+        println!("Usage: {} <server election address> <fault>", args[0]);
+        println!("       {} key", args[0]);
+        return;
+    }
+    let server: SocketAddr = args[1].parse().expect("Failed to parse server address");
+    let fault = args[2..].join(" ");
+
+    let socket = UdpSocket::bind("0.0.0.0:0")
+        .await
+        .expect("Failed to bind to ip");
+    let order = FaultOrder {
+        fault: fault.clone(),
+        issued_millis: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as u64),
+    };
+    let msg = Msg {
+        sender: socket.local_addr().unwrap(),
+        receiver: server,
+        msg_type: Type::InjectFault(Signed::sign(order, &identity)),
+        payload: None,
+    };
+    let serialized_msg = serde_cbor::ser::to_vec(&msg).unwrap();
+    socket.send_to(&serialized_msg, server).await.unwrap();
+    println!("Sent {} to {}", fault, server);
+}
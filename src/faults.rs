@@ -0,0 +1,139 @@
+use rand::Rng;
+use std::collections::HashMap;
+use std::fs;
+use std::net::SocketAddr;
+use tokio::time::Duration;
+
+// Fault injection for chaos testing. Faults come from a schedule file
+// (FAULT_SCHEDULE, one "<secs after start> <fault>" per line) or from admin
+// messages (Type::InjectFault, see fault_admin), both in the syntax of `parse`.
+// Admin messages are only accepted by servers given the admin's public key
+// (FAULT_ADMIN_KEY) and must be signed with it.
+
+// the sockets whose incoming packets can be dropped
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum SocketKind {
+    Service,
+    Election,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Fault {
+    Crash,                 // crash-stop, the process exits
+    Pause(u64),            // secs, ignore everything as if down
+    Drop(SocketKind, u32), // percent of incoming packets, 0 stops dropping
+    Partition(SocketAddr), // a peer's election address, drop everything coming from it (one-way)
+    Heal(SocketAddr),      // undo a partition
+    SlowEncryption(u64),   // millis added to every encryption, 0 stops
+    Clear,                 // undo every fault but a crash
+}
+
+impl Fault {
+    // crash | pause <secs> | drop <service|election> <percent> | partition <peer>
+    // | heal <peer> | slow <millis> | clear
+    pub fn parse(input: &str) -> Option<Fault> {
+        let words: Vec<&str> = input.split_whitespace().collect();
+        let fault = match words.as_slice() {
+            ["crash"] => Fault::Crash,
+            ["pause", secs] => Fault::Pause(secs.parse().ok()?),
+            ["drop", socket, percent] => {
+                let socket = match *socket {
+                    "service" => SocketKind::Service,
+                    "election" => SocketKind::Election,
+                    _ => return None,
+                };
+                Fault::Drop(socket, percent.parse::<u32>().ok()?.min(100))
+            }
+            ["partition", peer] => Fault::Partition(peer.parse().ok()?),
+            ["heal", peer] => Fault::Heal(peer.parse().ok()?),
+            ["slow", millis] => Fault::SlowEncryption(millis.parse().ok()?),
+            ["clear"] => Fault::Clear,
+            _ => return None,
+        };
+        Some(fault)
+    }
+}
+
+// Reads a schedule file, sorted by time. Blank lines and lines starting with #
+// are skipped, invalid ones are reported and skipped.
+pub fn load_schedule(path: &str) -> Vec<(u64, Fault)> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) => {
+            println!("Cannot read fault schedule {}: {}", path, e);
+            return Vec::new();
+        }
+    };
+    let mut schedule = Vec::new();
+    for line in contents.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let entry = line.split_once(' ').and_then(|(secs, fault)| {
+            Some((secs.trim().parse::<u64>().ok()?, Fault::parse(fault)?))
+        });
+        match entry {
+            Some(entry) => schedule.push(entry),
+            None => println!("Invalid fault schedule line: {}", line),
+        }
+    }
+    schedule.sort_by_key(|(secs, _)| *secs);
+    schedule
+}
+
+// The faults in effect. Pauses and crashes are carried out by the server itself,
+// `paused` drops everything while a pause lasts.
+#[derive(Clone, Debug, Default)]
+pub struct Faults {
+    pub paused: bool,
+    drop: HashMap<SocketKind, u32>,
+    partitioned: HashMap<SocketAddr, Vec<SocketAddr>>, // peer -> its addresses
+    pub slow_encryption: Option<Duration>,
+}
+
+impl Faults {
+    // `addrs` are the service, election and send addresses of a partition's peer
+    pub fn apply(&mut self, fault: Fault, addrs: Vec<SocketAddr>) {
+        match fault {
+            Fault::Drop(socket, 0) => {
+                self.drop.remove(&socket);
+            }
+            Fault::Drop(socket, percent) => {
+                self.drop.insert(socket, percent);
+            }
+            Fault::Partition(peer) => {
+                self.partitioned.insert(peer, addrs);
+            }
+            Fault::Heal(peer) => {
+                self.partitioned.remove(&peer);
+            }
+            Fault::SlowEncryption(0) => self.slow_encryption = None,
+            Fault::SlowEncryption(millis) => {
+                self.slow_encryption = Some(Duration::from_millis(millis))
+            }
+            Fault::Clear => {
+                *self = Faults {
+                    paused: self.paused,
+                    ..Faults::default()
+                }
+            }
+            Fault::Crash | Fault::Pause(_) => {}
+        }
+    }
+
+    // whether a packet that came in on `socket` from `src_addr` is to be dropped
+    pub fn should_drop(&self, socket: SocketKind, src_addr: SocketAddr) -> bool {
+        if self.paused
+            || self
+                .partitioned
+                .values()
+                .any(|addrs| addrs.contains(&src_addr))
+        {
+            return true;
+        }
+        match self.drop.get(&socket) {
+            Some(percent) => rand::thread_rng().gen_range(0..100) < *percent,
+            None => false,
+        }
+    }
+}
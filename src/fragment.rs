@@ -217,7 +217,8 @@ pub async fn server_send(
     }
 }
 
-pub async fn receive_all(socket: Arc<UdpSocket>) -> Vec<u8> {
+// Err with the server's reason if the request was refused
+pub async fn receive_all(socket: Arc<UdpSocket>) -> Result<Vec<u8>, String> {
     let mut buffer = [0; BUFFER_SIZE];
 
     let mut map: HashMap<String, BigMessage> = HashMap::new();
@@ -230,6 +231,7 @@ pub async fn receive_all(socket: Arc<UdpSocket>) -> Vec<u8> {
                 if let Ok(msg) = serde_cbor::de::from_slice::<Msg>(&buffer[..bytes_read]) {
                     let frag = match msg.msg_type {
                         Type::Fragment(frag) => frag,
+                        Type::Refused(reason) => return Err(reason),
                         _ => {
                             trace!("Could not parse fragment");
                             continue;
@@ -292,7 +294,7 @@ pub async fn receive_all(socket: Arc<UdpSocket>) -> Vec<u8> {
                             trace!("Full message is received!");
                             let msg = msg.to_owned();
                             e.insert(msg.clone());
-                            return msg.data;
+                            return Ok(msg.data);
                         }
                         e.insert(msg);
                     } else {
@@ -339,7 +341,7 @@ pub async fn receive_all(socket: Arc<UdpSocket>) -> Vec<u8> {
                         if new_frag && big_msg.received_len == big_msg.msg_len {
                             trace!("Full message is received!");
                             let big_msg = big_msg.to_owned();
-                            return big_msg.data;
+                            return Ok(big_msg.data);
                         }
                     }
                 } else {
@@ -0,0 +1,152 @@
+use crate::commons::Signed;
+use crate::utils::write_atomic;
+use chacha20poly1305::aead::{AeadInPlace, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce, Tag};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use rand::rngs::OsRng;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::{collections::HashMap, fs, net::SocketAddr};
+use x25519_dalek::{EphemeralSecret, PublicKey, StaticSecret};
+
+// Long-term identity of a client, used to sign the access manifests of the
+// images it shares. The secret key is kept next to the pictures, one per
+// client address so several clients can run from the same directory.
+pub struct Identity {
+    signing_key: SigningKey,
+}
+
+impl Identity {
+    pub fn load_or_create(path: &str) -> Identity {
+        if let Ok(bytes) = fs::read(path) {
+            if let Ok(secret) = <[u8; 32]>::try_from(bytes.as_slice()) {
+                return Identity {
+                    signing_key: SigningKey::from_bytes(&secret),
+                };
+            }
+            println!("Identity key {} is corrupted, generating a new one", path);
+        }
+
+        let signing_key = SigningKey::generate(&mut OsRng);
+        fs::write(path, signing_key.to_bytes()).expect("Failed to write identity key");
+        println!("Generated new identity key {}", path);
+        Identity { signing_key }
+    }
+
+    pub fn public_key(&self) -> [u8; 32] {
+        self.signing_key.verifying_key().to_bytes()
+    }
+
+    pub fn sign(&self, msg: &[u8]) -> Vec<u8> {
+        self.signing_key.sign(msg).to_bytes().to_vec()
+    }
+
+    // Decrypts in place what `seal` encrypted to this identity's public key,
+    // false if the image was sealed for someone else or has been tampered with.
+    pub fn open(&self, ephemeral_key: &[u8; 32], data: &mut [u8], tag: &[u8]) -> bool {
+        if tag.len() != 16 {
+            return false;
+        }
+        let secret = StaticSecret::from(self.signing_key.to_scalar_bytes());
+        let shared = secret.diffie_hellman(&PublicKey::from(*ephemeral_key));
+        let cipher = ChaCha20Poly1305::new(&seal_key(
+            shared.as_bytes(),
+            ephemeral_key,
+            &self.public_key(),
+        ));
+        cipher
+            .decrypt_in_place_detached(&Nonce::default(), &[], data, Tag::from_slice(tag))
+            .is_ok()
+    }
+}
+
+// Encrypts `data` in place so that only the holder of the identity with the given
+// (ed25519) public key can open it. The length is preserved, so the sealed bytes
+// fit wherever the plain ones did; the ephemeral key and the tag are returned
+// separately. None if `recipient` is not a valid public key.
+pub fn seal(recipient: &[u8; 32], data: &mut [u8]) -> Option<([u8; 32], Vec<u8>)> {
+    let recipient_x25519 = VerifyingKey::from_bytes(recipient).ok()?.to_montgomery();
+    let ephemeral = EphemeralSecret::random_from_rng(OsRng);
+    let ephemeral_key = PublicKey::from(&ephemeral).to_bytes();
+    let shared = ephemeral.diffie_hellman(&PublicKey::from(recipient_x25519.to_bytes()));
+
+    // a fresh ephemeral key per image, so a fixed nonce is never reused with the same key
+    let cipher = ChaCha20Poly1305::new(&seal_key(shared.as_bytes(), &ephemeral_key, recipient));
+    let tag = cipher
+        .encrypt_in_place_detached(&Nonce::default(), &[], data)
+        .ok()?;
+    Some((ephemeral_key, tag.to_vec()))
+}
+
+fn seal_key(shared: &[u8; 32], ephemeral_key: &[u8; 32], recipient: &[u8; 32]) -> Key {
+    let mut hasher = Sha256::new();
+    hasher.update(shared);
+    hasher.update(ephemeral_key);
+    hasher.update(recipient);
+    hasher.finalize()
+}
+
+pub fn verify(public_key: &[u8; 32], msg: &[u8], signature: &[u8]) -> bool {
+    let key = match VerifyingKey::from_bytes(public_key) {
+        Ok(key) => key,
+        Err(_) => return false,
+    };
+    match Signature::from_slice(signature) {
+        Ok(signature) => key.verify(msg, &signature).is_ok(),
+        Err(_) => false,
+    }
+}
+
+impl<T: Serialize> Signed<T> {
+    pub fn sign(value: T, identity: &Identity) -> Signed<T> {
+        let signature = identity.sign(&serde_cbor::to_vec(&value).unwrap());
+        Signed { value, signature }
+    }
+
+    pub fn verify(&self, public_key: &[u8; 32]) -> bool {
+        verify(
+            public_key,
+            &serde_cbor::to_vec(&self.value).unwrap(),
+            &self.signature,
+        )
+    }
+}
+
+// Public keys of image owners, pinned the first time an image from that owner
+// is received (trust on first use) and persisted so they survive restarts.
+pub struct Keyring {
+    path: String,
+    keys: HashMap<SocketAddr, [u8; 32]>,
+}
+
+impl Keyring {
+    pub fn load(path: &str) -> Keyring {
+        let keys = fs::read(path)
+            .ok()
+            .and_then(|bytes| serde_cbor::from_slice(&bytes).ok())
+            .unwrap_or_default();
+        Keyring {
+            path: String::from(path),
+            keys,
+        }
+    }
+
+    pub fn get(&self, owner: &SocketAddr) -> Option<[u8; 32]> {
+        self.keys.get(owner).copied()
+    }
+
+    // false if a different key is already pinned for this owner
+    pub fn pin(&mut self, owner: SocketAddr, key: [u8; 32]) -> bool {
+        match self.keys.get(&owner) {
+            Some(pinned) => *pinned == key,
+            None => {
+                self.keys.insert(owner, key);
+                let bytes = serde_cbor::to_vec(&self.keys).unwrap();
+                if let Err(e) = write_atomic(&self.path, &bytes) {
+                    println!("Failed to save keyring {}: {}", self.path, e);
+                }
+                true
+            }
+        }
+    }
+}
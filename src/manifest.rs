@@ -0,0 +1,182 @@
+use crate::commons::{ShareTerms, Signed};
+use crate::encryption::{read_manifest_block, write_manifest_block};
+use crate::identity::{self, Identity};
+use crate::watermark::WatermarkMode;
+use image::{ImageBuffer, Rgba};
+use serde_derive::{Deserialize, Serialize};
+use std::fmt;
+use std::net::SocketAddr;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Permissions {
+    pub view: bool,
+    pub request_more: bool,
+    // every view needs a one-time token from the owner (or the cloud acting for it)
+    pub online_views: bool,
+    // viewer and time are stamped onto the decoded image before it is shown
+    pub watermark: Option<WatermarkMode>,
+}
+
+impl Default for Permissions {
+    fn default() -> Self {
+        Permissions {
+            view: true,
+            request_more: true,
+            online_views: false,
+            watermark: None,
+        }
+    }
+}
+
+// Terms under which an owner shares an image with one recipient. The secret is
+// sealed to the recipient's identity key; the ephemeral key and tag needed to
+// open it are part of the signed terms.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AccessManifest {
+    pub owner: SocketAddr,
+    pub recipient: SocketAddr,
+    pub recipient_key: [u8; 32],
+    pub ephemeral_key: [u8; 32],
+    pub tag: Vec<u8>,
+    pub img_name: String,
+    pub views: u32,
+    pub expiry: Option<u64>, // unix time (secs)
+    pub permissions: Permissions,
+    pub issued_at: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SignedManifest {
+    pub manifest: AccessManifest,
+    pub owner_key: [u8; 32],
+    pub signature: Vec<u8>,
+}
+
+impl SignedManifest {
+    pub fn sign(manifest: AccessManifest, identity: &Identity) -> SignedManifest {
+        let signature = identity.sign(&serde_cbor::to_vec(&manifest).unwrap());
+        SignedManifest {
+            manifest,
+            owner_key: identity.public_key(),
+            signature,
+        }
+    }
+
+    pub fn verify(&self, owner_key: &[u8; 32]) -> bool {
+        let msg = serde_cbor::to_vec(&self.manifest).unwrap();
+        identity::verify(owner_key, &msg, &self.signature)
+    }
+}
+
+// What is embedded in a shared image: the owner signed grant, the owner's latest
+// signed terms (views and expiry) and the number of views left, which the
+// recipient's client counts down from the views in the terms (or, in online views
+// mode, the count last reported by the owner).
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ManifestRecord {
+    pub grant: SignedManifest,
+    pub terms: Signed<ShareTerms>,
+    pub remaining: u32,
+}
+
+// How this client shares its images: the permissions of every new share and how
+// long it lasts (secs, None never expires).
+#[derive(Clone, Debug, Default)]
+pub struct SharePolicy {
+    pub permissions: Permissions,
+    pub duration: Option<u64>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum AccessDenied {
+    NoManifest,
+    UnknownOwner,
+    BadSignature,
+    WrongRecipient,
+    WrongIdentity,
+    Expired,
+    NotPermitted,
+    Tampered,
+    NoViews,
+    Corrupted,
+    Unreachable,
+}
+
+impl fmt::Display for AccessDenied {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self {
+            AccessDenied::NoManifest => "the image carries no valid access manifest",
+            AccessDenied::UnknownOwner => "the owner's key is unknown or does not match",
+            AccessDenied::BadSignature => "the access manifest is not signed by the owner",
+            AccessDenied::WrongRecipient => "the image was shared with someone else",
+            AccessDenied::WrongIdentity => "the image is sealed to another identity key",
+            AccessDenied::Expired => "access to the image has expired",
+            AccessDenied::NotPermitted => "viewing is not permitted",
+            AccessDenied::Tampered => "more views left than the owner granted",
+            AccessDenied::NoViews => "no remaining views",
+            AccessDenied::Corrupted => "the image is corrupted or has missing parts",
+            AccessDenied::Unreachable => "neither the owner nor the cloud answered",
+        };
+        write!(f, "{}", reason)
+    }
+}
+
+impl ManifestRecord {
+    pub fn new(grant: SignedManifest, terms: Signed<ShareTerms>) -> ManifestRecord {
+        let remaining = terms.value.views;
+        ManifestRecord {
+            grant,
+            terms,
+            remaining,
+        }
+    }
+
+    pub fn read(img: &ImageBuffer<Rgba<u8>, Vec<u8>>) -> Option<ManifestRecord> {
+        serde_cbor::from_slice(&read_manifest_block(img)?).ok()
+    }
+
+    pub fn write(&self, img: &mut ImageBuffer<Rgba<u8>, Vec<u8>>) -> bool {
+        write_manifest_block(img, &serde_cbor::to_vec(self).unwrap())
+    }
+
+    // everything but the view count, checked on receipt and before every view
+    pub fn check_grant(
+        &self,
+        owner_key: Option<[u8; 32]>,
+        owner: SocketAddr,
+        recipient: SocketAddr,
+        recipient_key: &[u8; 32],
+        img_name: &str,
+        now: u64,
+    ) -> Result<(), AccessDenied> {
+        let owner_key = owner_key.ok_or(AccessDenied::UnknownOwner)?;
+        if owner_key != self.grant.owner_key {
+            return Err(AccessDenied::UnknownOwner);
+        }
+        if !self.grant.verify(&owner_key) || !self.terms.verify(&owner_key) {
+            return Err(AccessDenied::BadSignature);
+        }
+        let manifest = &self.grant.manifest;
+        let terms = &self.terms.value;
+        if manifest.owner != owner
+            || manifest.recipient != recipient
+            || manifest.img_name != img_name
+            || terms.img_id != format!("{}&{}&{}", owner, recipient, img_name)
+        {
+            return Err(AccessDenied::WrongRecipient);
+        }
+        if manifest.recipient_key != *recipient_key {
+            return Err(AccessDenied::WrongIdentity);
+        }
+        if terms.expiry.is_some_and(|expiry| now >= expiry) {
+            return Err(AccessDenied::Expired);
+        }
+        if !manifest.permissions.view || terms.revoked {
+            return Err(AccessDenied::NotPermitted);
+        }
+        if self.remaining > terms.views {
+            return Err(AccessDenied::Tampered);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,85 @@
+use std::fmt;
+use sysinfo::{CpuExt, System, SystemExt};
+
+// What a server's election priority is made of. CPU and memory are sampled
+// periodically, the encryption figures are kept up to date by the service loop.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LoadSample {
+    pub cpu_usage: f32,       // 0..1, over all cores
+    pub memory_used: f32,     // 0..1
+    pub in_flight_bytes: u64, // secrets being encrypted
+    pub queue_depth: usize,   // encryption jobs not finished yet
+}
+
+impl LoadSample {
+    pub fn sample_system(&mut self, sys: &mut System) {
+        sys.refresh_cpu();
+        sys.refresh_memory();
+        self.cpu_usage = sys.global_cpu_info().cpu_usage() / 100.0;
+        self.memory_used = match sys.total_memory() {
+            0 => 0.0,
+            total => 1.0 - sys.available_memory() as f32 / total as f32,
+        };
+    }
+
+    // Higher is better: 16 for an idle server, minus the weighted load.
+    pub fn priority(&self, weights: &PriorityWeights) -> f32 {
+        16.0 - weights.cpu * self.cpu_usage
+            - weights.memory * self.memory_used
+            - weights.bytes * (self.in_flight_bytes as f32 / (1024.0 * 1024.0))
+            - weights.queue * self.queue_depth as f32
+    }
+}
+
+impl fmt::Display for LoadSample {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "cpu {:.0}%, memory {:.0}%, {:.1} MiB in flight, {} queued",
+            self.cpu_usage * 100.0,
+            self.memory_used * 100.0,
+            self.in_flight_bytes as f32 / (1024.0 * 1024.0),
+            self.queue_depth
+        )
+    }
+}
+
+// How much each part of the load costs in priority: per unit of CPU and memory
+// use, per MiB in flight and per queued job.
+#[derive(Clone, Copy, Debug)]
+pub struct PriorityWeights {
+    pub cpu: f32,
+    pub memory: f32,
+    pub bytes: f32,
+    pub queue: f32,
+}
+
+impl Default for PriorityWeights {
+    fn default() -> PriorityWeights {
+        PriorityWeights {
+            cpu: 8.0,
+            memory: 4.0,
+            bytes: 0.5,
+            queue: 1.0,
+        }
+    }
+}
+
+impl PriorityWeights {
+    // Parses "cpu=8,memory=4,bytes=0.5,queue=1", weights left out keep their default.
+    pub fn parse(input: &str) -> Option<PriorityWeights> {
+        let mut weights = PriorityWeights::default();
+        for pair in input.split(',').filter(|pair| !pair.trim().is_empty()) {
+            let (name, value) = pair.split_once('=')?;
+            let value: f32 = value.trim().parse().ok()?;
+            match name.trim() {
+                "cpu" => weights.cpu = value,
+                "memory" => weights.memory = value,
+                "bytes" => weights.bytes = value,
+                "queue" => weights.queue = value,
+                _ => return None,
+            }
+        }
+        Some(weights)
+    }
+}
@@ -0,0 +1,566 @@
+use crate::commons::{Command, LogEntry, Type};
+use crate::utils::write_atomic;
+use rand::Rng;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::net::SocketAddr;
+use tokio::time::{Duration, Instant};
+
+// A Raft-like replicated log of the updates to the directory of service. The
+// log leader appends the commands it gets and replicates them to the peers, an
+// entry is committed once a majority of the servers has it, and every server
+// applies the committed entries in log order. Terms, votes and entries are
+// saved before anything is sent, so a restarted server keeps its promises.
+//
+// This only keeps the state: every call returns the messages to send, as
+// (election address, msg type), and the server sends them.
+
+pub const LOG_HEARTBEAT_MILLIS: u64 = 300;
+// a follower that hears nothing from a leader for this long (randomized
+// between the two) starts an election
+const LOG_ELECTION_MIN_MILLIS: u64 = 1500;
+const LOG_ELECTION_MAX_MILLIS: u64 = 3000;
+// entries per append, so an append fits in one datagram
+const MAX_APPEND_ENTRIES: usize = 8;
+// commands kept while no leader is known, the oldest go first
+const MAX_BUFFERED_COMMANDS: usize = 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct PersistentState {
+    term: u64,
+    voted_for: Option<SocketAddr>,
+    entries: Vec<LogEntry>,
+}
+
+pub struct ReplicatedLog {
+    path: String,
+    own_addr: SocketAddr,
+    configured: Vec<SocketAddr>, // the peers we started with, the log's config entries apply on top
+    peers: Vec<SocketAddr>,      // election addresses, as of the last config entry
+    term: u64,
+    voted_for: Option<SocketAddr>,
+    entries: Vec<LogEntry>, // entry i has index i + 1
+    commit_index: usize,
+    last_applied: usize,
+    role: Role,
+    leader: Option<SocketAddr>,
+    votes: HashSet<SocketAddr>,
+    next_index: HashMap<SocketAddr, usize>, // leader: the next entry to send to each peer
+    match_index: HashMap<SocketAddr, usize>, // leader: the last entry known to be on each peer
+    deadline: Instant,                      // when to start an election
+    requests: HashSet<String>,              // the client requests in the log, appended once
+    buffered: Vec<(Option<String>, Command)>, // proposed while no leader was known
+}
+
+type Outbox = Vec<(SocketAddr, Type)>;
+
+impl ReplicatedLog {
+    pub fn load(path: &str, own_addr: SocketAddr, peers: Vec<SocketAddr>) -> ReplicatedLog {
+        let state: PersistentState = match fs::read(path) {
+            Ok(bytes) => serde_cbor::from_slice(&bytes).unwrap_or_else(|e| {
+                println!(
+                    "Replicated log {} is corrupted, starting empty: {}",
+                    path, e
+                );
+                PersistentState::default()
+            }),
+            Err(_) => PersistentState::default(),
+        };
+        println!(
+            "Replicated log {}: term {}, {} entries",
+            path,
+            state.term,
+            state.entries.len()
+        );
+        let requests = requests_in(&state.entries);
+        let mut log = ReplicatedLog {
+            path: String::from(path),
+            own_addr,
+            configured: peers,
+            peers: Vec::new(),
+            term: state.term,
+            voted_for: state.voted_for,
+            entries: state.entries,
+            commit_index: 0,
+            last_applied: 0,
+            role: Role::Follower,
+            leader: None,
+            votes: HashSet::new(),
+            next_index: HashMap::new(),
+            match_index: HashMap::new(),
+            deadline: election_deadline(),
+            requests,
+            buffered: Vec::new(),
+        };
+        log.refresh_peers();
+        log
+    }
+
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    pub fn term(&self) -> u64 {
+        self.term
+    }
+
+    pub fn leader(&self) -> Option<SocketAddr> {
+        self.leader
+    }
+
+    pub fn commit_index(&self) -> usize {
+        self.commit_index
+    }
+
+    // The peers we start from, what a joining server is told by the members.
+    // Changes after that come as config entries in the log.
+    pub fn set_peers(&mut self, peers: Vec<SocketAddr>) {
+        self.configured = peers;
+        self.refresh_peers();
+    }
+
+    // Every server, ourselves included: the configured ones, then the config
+    // entries in log order. As in Raft, the last entry counts as soon as it is
+    // in the log, committed or not.
+    pub fn config(&self) -> Vec<SocketAddr> {
+        let mut config = self.configured.clone();
+        config.push(self.own_addr);
+        for entry in &self.entries {
+            match entry.command {
+                Command::AddServer(_, election_addr, _) if !config.contains(&election_addr) => {
+                    config.push(election_addr);
+                }
+                Command::RemoveServer(election_addr) => {
+                    config.retain(|server| *server != election_addr);
+                }
+                _ => {}
+            }
+        }
+        config
+    }
+
+    fn refresh_peers(&mut self) {
+        let own_addr = self.own_addr;
+        let peers: Vec<SocketAddr> = self
+            .config()
+            .into_iter()
+            .filter(|server| *server != own_addr)
+            .collect();
+        let next = self.last_index() + 1;
+        for peer in &peers {
+            self.next_index.entry(*peer).or_insert(next);
+            self.match_index.entry(*peer).or_insert(0);
+        }
+        self.next_index.retain(|peer, _| peers.contains(peer));
+        self.match_index.retain(|peer, _| peers.contains(peer));
+        self.votes
+            .retain(|voter| *voter == self.own_addr || peers.contains(voter));
+        self.peers = peers;
+    }
+
+    fn majority(&self) -> usize {
+        let servers = self.peers.len() + 1;
+        servers / 2 + 1
+    }
+
+    fn last_index(&self) -> usize {
+        self.entries.len()
+    }
+
+    // the term of the entry at `index`, 0 before the first entry
+    fn term_at(&self, index: usize) -> u64 {
+        match index {
+            0 => 0,
+            index => self.entries[index - 1].term,
+        }
+    }
+
+    fn persist(&self) {
+        let state = PersistentState {
+            term: self.term,
+            voted_for: self.voted_for,
+            entries: self.entries.clone(),
+        };
+        let bytes = serde_cbor::to_vec(&state).unwrap();
+        write_atomic(&self.path, &bytes).expect("Failed to save the replicated log");
+    }
+
+    // a newer term, from anyone, turns us into a follower of that term
+    fn observe_term(&mut self, term: u64) {
+        if term > self.term {
+            self.term = term;
+            self.voted_for = None;
+            self.role = Role::Follower;
+            self.leader = None;
+            self.persist();
+        }
+    }
+
+    // Called every LOG_HEARTBEAT_MILLIS: the leader sends its appends, which are
+    // its heartbeats too, anyone else starts an election once its deadline passed.
+    pub fn tick(&mut self) -> Outbox {
+        if self.role == Role::Leader {
+            return self
+                .peers
+                .iter()
+                .map(|peer| self.append_for(*peer))
+                .collect();
+        }
+        if Instant::now() < self.deadline {
+            return Vec::new();
+        }
+        self.term += 1;
+        self.role = Role::Candidate;
+        self.leader = None;
+        self.voted_for = Some(self.own_addr);
+        self.votes = HashSet::from([self.own_addr]);
+        self.deadline = election_deadline();
+        self.persist();
+        println!("Log election for term {}", self.term);
+        if self.votes.len() >= self.majority() {
+            return self.become_leader();
+        }
+        let request = Type::LogVoteRequest(
+            self.term,
+            self.last_index(),
+            self.term_at(self.last_index()),
+        );
+        self.peers
+            .iter()
+            .map(|peer| (*peer, request.clone()))
+            .collect()
+    }
+
+    fn become_leader(&mut self) -> Outbox {
+        println!("Log leader for term {}", self.term);
+        self.role = Role::Leader;
+        self.leader = Some(self.own_addr);
+        self.next_index = self
+            .peers
+            .iter()
+            .map(|peer| (*peer, self.last_index() + 1))
+            .collect();
+        self.match_index = self.peers.iter().map(|peer| (*peer, 0)).collect();
+        // entries of earlier terms only commit along with one of our own
+        let mut outbox = self.propose(Command::Noop);
+        outbox.extend(self.flush_buffered());
+        outbox
+    }
+
+    // Appends a command if we are the leader and sends it on, otherwise forwards
+    // it to the leader. Without a known leader the command is kept until one is.
+    pub fn propose(&mut self, command: Command) -> Outbox {
+        self.propose_request(None, command)
+    }
+
+    // Clients send their updates to every server. With the client's request
+    // ("{client}:{id}") every copy is passed on and the leader appends the first
+    // one, so an update that reached only followers, or came during a failover,
+    // is not lost. Without it (older clients) only the leader's copy is appended.
+    pub fn propose_broadcast(&mut self, request: Option<String>, command: Command) -> Outbox {
+        if request.is_none() && self.role != Role::Leader {
+            return Vec::new();
+        }
+        self.propose_request(request, command)
+    }
+
+    fn propose_request(&mut self, request: Option<String>, command: Command) -> Outbox {
+        // a leader that is leaving will not append it, the next one will
+        let leaving =
+            matches!(command, Command::RemoveServer(server) if Some(server) == self.leader);
+        match (self.role, self.leader) {
+            (Role::Leader, _) => {
+                let config_change = match command {
+                    Command::AddServer(_, server, _) => Some(!self.config().contains(&server)),
+                    Command::RemoveServer(server) => Some(self.config().contains(&server)),
+                    _ => None,
+                };
+                if config_change == Some(false) {
+                    return Vec::new();
+                }
+                // one server at a time, so that the old and new majorities overlap
+                if config_change.is_some() && self.config_uncommitted() {
+                    self.buffer(request, command);
+                    return Vec::new();
+                }
+                if let Some(request) = &request {
+                    if !self.requests.insert(request.clone()) {
+                        return Vec::new();
+                    }
+                }
+                self.entries.push(LogEntry {
+                    term: self.term,
+                    command,
+                    request,
+                });
+                if config_change.is_some() {
+                    self.refresh_peers();
+                }
+                self.persist();
+                self.advance_commit();
+                self.peers
+                    .iter()
+                    .map(|peer| self.append_for(*peer))
+                    .collect()
+            }
+            (_, Some(leader)) if !leaving => {
+                vec![(leader, Type::LogForward(request, command))]
+            }
+            _ => {
+                println!("No log leader, buffering {:?}", command);
+                self.buffer(request, command);
+                Vec::new()
+            }
+        }
+    }
+
+    fn buffer(&mut self, request: Option<String>, command: Command) {
+        if self.buffered.len() == MAX_BUFFERED_COMMANDS {
+            self.buffered.remove(0);
+        }
+        self.buffered.push((request, command));
+    }
+
+    fn config_uncommitted(&self) -> bool {
+        self.entries[self.commit_index..].iter().any(|entry| {
+            matches!(
+                entry.command,
+                Command::AddServer(..) | Command::RemoveServer(_)
+            )
+        })
+    }
+
+    // proposes again what came while no leader was known
+    fn flush_buffered(&mut self) -> Outbox {
+        let buffered: Vec<_> = self.buffered.drain(..).collect();
+        buffered
+            .into_iter()
+            .flat_map(|(request, command)| self.propose_request(request, command))
+            .collect()
+    }
+
+    fn append_for(&self, peer: SocketAddr) -> (SocketAddr, Type) {
+        let next = self.next_index.get(&peer).copied().unwrap_or(1);
+        let prev = next - 1;
+        let end = self.last_index().min(prev + MAX_APPEND_ENTRIES);
+        let entries = self.entries[prev..end].to_vec();
+        (
+            peer,
+            Type::LogAppend(
+                self.term,
+                prev,
+                self.term_at(prev),
+                entries,
+                self.commit_index,
+            ),
+        )
+    }
+
+    // the highest entry of our term that a majority has is committed, and so is
+    // everything before it
+    fn advance_commit(&mut self) {
+        for index in (self.commit_index + 1..=self.last_index()).rev() {
+            if self.term_at(index) != self.term {
+                break;
+            }
+            let replicas = 1 + self
+                .match_index
+                .values()
+                .filter(|matched| **matched >= index)
+                .count();
+            if replicas >= self.majority() {
+                self.commit_index = index;
+                break;
+            }
+        }
+    }
+
+    // the log messages coming in on the election socket
+    pub fn handle(&mut self, src_addr: SocketAddr, msg_type: Type) -> Outbox {
+        match msg_type {
+            Type::LogVoteRequest(term, last_index, last_term) => {
+                self.handle_vote_request(src_addr, term, last_index, last_term)
+            }
+            Type::LogVote(term, granted) => self.handle_vote(src_addr, term, granted),
+            Type::LogAppend(term, prev_index, prev_term, entries, leader_commit) => self
+                .handle_append(
+                    src_addr,
+                    term,
+                    prev_index,
+                    prev_term,
+                    entries,
+                    leader_commit,
+                ),
+            Type::LogAppendReply(term, success, matched) => {
+                self.handle_append_reply(src_addr, term, success, matched)
+            }
+            // passed on (or kept) if we are not the leader any more
+            Type::LogForward(request, command) => self.propose_request(request, command),
+            _ => Vec::new(),
+        }
+    }
+
+    fn handle_vote_request(
+        &mut self,
+        src_addr: SocketAddr,
+        term: u64,
+        last_index: usize,
+        last_term: u64,
+    ) -> Outbox {
+        self.observe_term(term);
+        // only for a candidate whose log has everything we might have committed
+        let up_to_date =
+            (last_term, last_index) >= (self.term_at(self.last_index()), self.last_index());
+        let granted = term == self.term
+            && up_to_date
+            && (self.voted_for.is_none() || self.voted_for == Some(src_addr));
+        if granted {
+            self.voted_for = Some(src_addr);
+            self.deadline = election_deadline();
+            self.persist();
+        }
+        vec![(src_addr, Type::LogVote(self.term, granted))]
+    }
+
+    fn handle_vote(&mut self, src_addr: SocketAddr, term: u64, granted: bool) -> Outbox {
+        self.observe_term(term);
+        if self.role != Role::Candidate || term != self.term || !granted {
+            return Vec::new();
+        }
+        self.votes.insert(src_addr);
+        if self.votes.len() >= self.majority() {
+            return self.become_leader();
+        }
+        Vec::new()
+    }
+
+    fn handle_append(
+        &mut self,
+        src_addr: SocketAddr,
+        term: u64,
+        prev_index: usize,
+        prev_term: u64,
+        entries: Vec<LogEntry>,
+        leader_commit: usize,
+    ) -> Outbox {
+        self.observe_term(term);
+        if term < self.term {
+            return vec![(src_addr, Type::LogAppendReply(self.term, false, 0))];
+        }
+        if self.leader != Some(src_addr) {
+            println!("Log leader for term {} is {}", term, src_addr);
+        }
+        self.role = Role::Follower;
+        self.leader = Some(src_addr);
+        self.deadline = election_deadline();
+        let mut outbox = self.flush_buffered();
+
+        if prev_index > self.last_index() || self.term_at(prev_index) != prev_term {
+            // the leader backs up and tries again from before our last entry
+            let matched = self.last_index().min(prev_index.saturating_sub(1));
+            outbox.push((src_addr, Type::LogAppendReply(self.term, false, matched)));
+            return outbox;
+        }
+        let matched = prev_index + entries.len();
+        let mut changed = false;
+        for (offset, entry) in entries.into_iter().enumerate() {
+            let index = prev_index + 1 + offset;
+            if index <= self.last_index() {
+                if self.term_at(index) == entry.term {
+                    continue;
+                }
+                // a conflicting entry was never committed, drop it and what follows
+                self.entries.truncate(index - 1);
+                self.requests = requests_in(&self.entries);
+            }
+            if let Some(request) = &entry.request {
+                self.requests.insert(request.clone());
+            }
+            self.entries.push(entry);
+            changed = true;
+        }
+        if changed {
+            self.refresh_peers();
+            self.persist();
+        }
+        // a duplicated or resent append may cover less than we already committed
+        self.commit_index = self.commit_index.max(leader_commit.min(matched));
+        outbox.push((src_addr, Type::LogAppendReply(self.term, true, matched)));
+        outbox
+    }
+
+    fn handle_append_reply(
+        &mut self,
+        src_addr: SocketAddr,
+        term: u64,
+        success: bool,
+        matched: usize,
+    ) -> Outbox {
+        self.observe_term(term);
+        if self.role != Role::Leader || term != self.term {
+            return Vec::new();
+        }
+        if success {
+            // replies can arrive late or twice, the peer's progress never goes back
+            let known = self.match_index.entry(src_addr).or_insert(0);
+            *known = (*known).max(matched);
+            let known = *known;
+            let next = self.next_index.entry(src_addr).or_insert(1);
+            *next = (*next).max(known + 1);
+            let committed = self.commit_index;
+            self.advance_commit();
+            // a config change waiting for the last one to commit
+            let mut outbox = Vec::new();
+            if self.commit_index > committed {
+                outbox = self.flush_buffered();
+            }
+            if known < self.last_index() {
+                outbox.push(self.append_for(src_addr));
+            }
+            return outbox;
+        }
+        let next = self.next_index.get(&src_addr).copied().unwrap_or(1);
+        self.next_index
+            .insert(src_addr, (matched + 1).min(next.saturating_sub(1)).max(1));
+        vec![self.append_for(src_addr)]
+    }
+
+    // The committed commands not applied yet, in log order, each with an id that
+    // is the same on every server: the client request it comes from, else its
+    // place in the log.
+    pub fn take_committed(&mut self) -> Vec<(String, Command)> {
+        let committed = self.entries[self.last_applied..self.commit_index]
+            .iter()
+            .zip(self.last_applied..)
+            .map(|(entry, index)| {
+                let id = entry.request.clone();
+                (
+                    id.unwrap_or(format!("log#{}", index)),
+                    entry.command.clone(),
+                )
+            })
+            .collect();
+        self.last_applied = self.commit_index;
+        committed
+    }
+}
+
+fn requests_in(entries: &[LogEntry]) -> HashSet<String> {
+    entries
+        .iter()
+        .filter_map(|entry| entry.request.clone())
+        .collect()
+}
+
+fn election_deadline() -> Instant {
+    let millis = rand::thread_rng().gen_range(LOG_ELECTION_MIN_MILLIS..LOG_ELECTION_MAX_MILLIS);
+    Instant::now() + Duration::from_millis(millis)
+}
@@ -3,6 +3,7 @@
     unused_variables,
     unused_imports,
     clippy::redundant_allocation,
+    clippy::too_many_arguments,
     unused_assignments
 )]
 
@@ -14,41 +15,202 @@ use rand::Rng;
 use std::env;
 use std::net::SocketAddr;
 use std::sync::Arc;
-use tokio::sync::mpsc;
-use tokio::time::{sleep, Duration};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::{mpsc, Notify};
+use tokio::time::{sleep, Duration, Instant};
 use tokio::{net::UdpSocket, sync::Mutex};
 
+use std::collections::BTreeSet;
 use std::collections::HashMap;
 use std::collections::HashSet;
 
-use sysinfo::{CpuExt, CpuRefreshKind, RefreshKind, System, SystemExt};
+use sysinfo::{CpuRefreshKind, RefreshKind, System, SystemExt};
 
 mod dir_of_service;
-use dir_of_service::ServerDirOfService;
+use dir_of_service::{parse_img_id, split_state, ServerDirOfService};
 mod commons;
 use commons::BUFFER_SIZE;
+use commons::COVER_IMAGES_PATH;
+use commons::EMBEDDING_KEY_FILEPATH;
 use commons::SERVERS_FILEPATH;
-use commons::{Msg, Type};
+use commons::SERVER_KEYRING_FILEPATH;
+use commons::{
+    Command, DirState, FaultOrder, Msg, OnlineGrant, Signed, Type, REPLICATED_LOG_FILEPATH,
+};
 mod fragment;
 use fragment::BigMessage;
 mod encryption;
-use encryption::ImageLoader;
+use encryption::{CoverPool, PixelOrder, MANIFEST_LEN, PART_HEADER_LEN};
+mod cover_gen;
+mod faults;
+use faults::{Fault, Faults, SocketKind};
+mod identity;
+use identity::Keyring;
+mod priority;
+use priority::{LoadSample, PriorityWeights};
+mod replog;
+use replog::{ReplicatedLog, LOG_HEARTBEAT_MILLIS};
 mod utils;
 
+// Where the covers of a request come from: picked from the cover pool, or
+// generated from a per request seed and sized exactly to the secret.
+enum Covers {
+    Pool(Vec<DynamicImage>),
+    Generated(u64),
+}
+
+// how many served request ids are kept per client before they are folded into the floor
+const RECENT_COMPLETED_IDS: usize = 1024;
+
+// Request ids of one client that have been served, so replays can be refused.
+// Clients hand out ids in order, so only the most recent ones are kept and
+// everything older is covered by a floor.
+#[derive(Clone, Default)]
+struct CompletedIds {
+    floor: Option<u32>, // every id up to and including this one
+    recent: BTreeSet<u32>,
+}
+
+impl CompletedIds {
+    fn contains(&self, id: u32) -> bool {
+        self.floor.is_some_and(|floor| id <= floor) || self.recent.contains(&id)
+    }
+
+    fn insert(&mut self, id: u32) {
+        if self.contains(id) {
+            return;
+        }
+        self.recent.insert(id);
+        while self.recent.len() > RECENT_COMPLETED_IDS {
+            self.floor = self.recent.pop_first();
+        }
+    }
+}
+
+// req_ids are "{client addr}:{id}"
+fn parse_req_id(req_id: &str) -> Option<(SocketAddr, u32)> {
+    let (addr, id) = req_id.rsplit_once(':')?;
+    Some((addr.parse().ok()?, id.parse().ok()?))
+}
+
+// how long a lease lasts without renewal, and how often the leader renews it
+const LEASE_MILLIS: u64 = 3000;
+const LEASE_RENEW_MILLIS: u64 = 1000;
+// how often CPU and memory use are sampled for the election priority
+const LOAD_SAMPLE_MILLIS: u64 = 500;
+// priority taken off a server per assigned request, until its next load report
+const ASSIGN_PENALTY: f32 = 0.5;
+
+// Lease mode: the winner of an election stays the leader until its lease runs
+// out, and assigns client requests to servers itself instead of an election
+// per request. It renews the lease while it is alive.
+#[derive(Clone, Copy)]
+struct Lease {
+    leader: SocketAddr, // election address
+    expires: Instant,
+}
+
+// Election state older than the timeout is swept: the election is restarted if
+// a client is still waiting for it (up to a few times), abandoned otherwise.
+const ELECTION_TIMEOUT_MILLIS: u64 = 3000;
+const ELECTION_SWEEP_MILLIS: u64 = 1000;
+const MAX_ELECTION_RESTARTS: u32 = 2;
+
+// Servers send heartbeats to their peers on the election socket, and suspect a
+// peer dead when nothing came from it for a while.
+const HEARTBEAT_MILLIS: u64 = 500;
+const SUSPECT_AFTER_MILLIS: u64 = 2000;
+
+// Gossip mode: every round, a server merges directory states with a random
+// live peer.
+const ANTI_ENTROPY_MILLIS: u64 = 2000;
+// leaves room in the 8192 bytes election buffer for the rest of the message
+const DIR_STATE_PART_BYTES: usize = 4096;
+
+// how often a new server asks to join until it hears back
+const JOIN_RETRY_MILLIS: u64 = 1000;
+
+// how often the cover directory is rescanned for added, changed or removed covers
+const COVER_RESCAN_MILLIS: u64 = 5000;
+
+// how long a state change waits for a majority to acknowledge a quorum probe
+const QUORUM_PROBE_MILLIS: u64 = 500;
+
+// how far an admin fault's issue time may be from ours, clock skew included
+const FAULT_ORDER_MAX_AGE_MILLIS: u64 = 30000;
+
+#[derive(Clone, Copy)]
+struct ElectionAge {
+    started: Instant,
+    restarts: u32,
+}
+
+// what happened to elections, since the server started
+#[derive(Clone, Copy, Debug, Default)]
+struct ElectionMetrics {
+    completed: u64,
+    restarted: u64,
+    abandoned: u64,
+}
+
 #[derive(Clone)]
 struct ServerStats {
     elections_initiated_by_me: HashSet<String>, // req_id -> (own_p, #anticipated Oks)
     elections_received_oks: HashSet<String>,    // req_id -> #currently received Oks
+    election_replies: HashMap<String, HashSet<SocketAddr>>, // req_id -> peers that answered
+    reply_notifiers: HashMap<String, Arc<Notify>>, // wakes the election waiting on req_id
+    quorum_probes: HashMap<u64, (HashSet<SocketAddr>, Arc<Notify>)>, // nonce -> peers that acknowledged
     running_elections: HashMap<String, f32>,
     requests_buffer: HashMap<String, Msg>,
+    election_ages: HashMap<String, ElectionAge>, // when the state of each req_id appeared
+    election_metrics: ElectionMetrics,
+    completed_requests: HashMap<SocketAddr, CompletedIds>,
+    lease_mode: bool,
+    lease: Option<Lease>,
+    loads: HashMap<SocketAddr, (f32, Instant)>, // election address -> priority, when reported
+    load: LoadSample,
+    weights: PriorityWeights,
     peer_servers: Vec<(SocketAddr, SocketAddr, SocketAddr)>,
+    left: HashSet<SocketAddr>, // election addresses of servers that left, late news does not bring them back
+    joining: bool,             // waiting for the members after asking to join
+    last_heartbeats: HashMap<SocketAddr, Instant>, // election address -> last heard from
+    suspected: HashSet<SocketAddr>, // election addresses of peers suspected dead
     own_ips: Option<(SocketAddr, SocketAddr, SocketAddr)>,
+    mode: String,
+    quorum: bool, // coordinate and change state only with a majority of the servers
+    gossip: bool, // replicate the directory by anti-entropy instead of the log
+    faults: Faults,
+    fault_admin: Option<[u8; 32]>, // the admin's public key, admin faults are refused without it
+    last_fault_order: u64,         // when the last accepted admin fault was issued
     down: bool,
 }
 
-async fn handle_ok_msg(req_id: String, stats: Arc<Mutex<ServerStats>>) {
+async fn handle_ok_msg(req_id: String, src_addr: SocketAddr, stats: Arc<Mutex<ServerStats>>) {
     let mut data = stats.lock().await;
-    data.elections_received_oks.insert(req_id);
+    data.elections_received_oks.insert(req_id.clone());
+    handle_election_reply(&mut data, req_id, src_addr);
+}
+
+// every answer to our election, OK or ack, counts towards the quorum
+fn handle_election_reply(data: &mut ServerStats, req_id: String, src_addr: SocketAddr) {
+    if let Some(replied) = data.reply_notifiers.get(&req_id) {
+        replied.notify_waiters();
+    }
+    data.election_replies
+        .entry(req_id)
+        .or_default()
+        .insert(src_addr);
+}
+
+async fn send_election_ack(req_id: String, src_addr: SocketAddr, socket: Arc<UdpSocket>) {
+    let msg = Msg {
+        sender: socket.local_addr().unwrap(),
+        receiver: src_addr,
+        msg_type: Type::ElectionAck,
+        payload: Some(req_id),
+    };
+    let serialized_msg = serde_cbor::ser::to_vec(&msg).unwrap();
+    socket.send_to(&serialized_msg, src_addr).await.unwrap();
 }
 
 async fn send_ok_msg(
@@ -59,7 +221,11 @@ async fn send_ok_msg(
 ) {
     println!("[{}] sending ok msg", req_id);
     let data = stats.lock().await;
-    let own_priority = *data.running_elections.get(&req_id).unwrap();
+    // the election may have been swept in the meantime
+    let own_priority = match data.running_elections.get(&req_id) {
+        Some(priority) => *priority,
+        None => return,
+    };
     let msg = Msg {
         sender: socket.local_addr().unwrap(),
         receiver: src_addr,
@@ -79,20 +245,20 @@ async fn handle_election(
     election_socket: Arc<UdpSocket>,
     stats: Arc<Mutex<ServerStats>>,
 ) {
-    let mut sys = System::new_with_specifics(
-        RefreshKind::new()
-            .with_cpu(CpuRefreshKind::everything())
-            .with_memory(),
-    );
-    sys.refresh_cpu();
-    let priority = 16.0 - sys.load_average().one as f32;
     let mut data = stats.lock().await;
+    let priority = data.priority();
+    // a late election message must not bring a served request back to life
+    if data.is_completed(&req_id) {
+        println!("[{}] Request already served, ignoring election", req_id);
+        return;
+    }
     let own_priority = data
         .running_elections
         .entry(req_id.clone())
         .or_insert(priority)
         .to_owned();
-    println!("[{}] Own priority {}", req_id, own_priority);
+    data.touch_election(&req_id);
+    println!("[{}] Own priority {} ({})", req_id, own_priority, data.load);
 
     let own_addr = election_socket.local_addr().unwrap().to_string();
     let other_addr = src_addr.to_string();
@@ -120,10 +286,18 @@ async fn handle_election(
             )
             .await;
         }
+    } else {
+        drop(data);
+        send_election_ack(req_id, src_addr, election_socket).await;
     }
 }
 
-async fn reply_to_client(socket: Arc<UdpSocket>, req_id: String, stats: Arc<Mutex<ServerStats>>) {
+// true if the client was answered, false if we never got its request
+async fn reply_to_client(
+    socket: Arc<UdpSocket>,
+    req_id: String,
+    stats: Arc<Mutex<ServerStats>>,
+) -> bool {
     let data = stats.lock().await;
     // let target_addr = data.requests_buffer.get(&req_id).unwrap().sender;
     match data.requests_buffer.get(&req_id) {
@@ -131,17 +305,29 @@ async fn reply_to_client(socket: Arc<UdpSocket>, req_id: String, stats: Arc<Mute
             let s = s.to_owned();
             println!("[{}] Replying to Client", req_id);
             let target_addr = s.sender;
-            let response = data.own_ips.unwrap().0.to_string();
-            println!("{}", response);
-            socket
-                .send_to(response.as_bytes(), target_addr)
-                .await
-                .unwrap();
+            let server = data.own_ips.unwrap().0;
+            println!("{}", server);
+            let servers = data.cloud_servers();
+            drop(data);
+            send_assignment(&socket, target_addr, server, servers).await;
+            true
         }
         None => {
             println!("[{}] Aborting replying to client", req_id);
+            false
         }
-    };
+    }
+}
+
+// Tells a client which server to use, along with the current cloud servers so
+// that it learns about servers joining and leaving.
+async fn send_assignment(
+    socket: &Arc<UdpSocket>,
+    client: SocketAddr,
+    server: SocketAddr,
+    servers: Vec<(SocketAddr, SocketAddr)>,
+) {
+    send_to_peer(socket, client, Type::ServerAssignment(server, servers)).await;
 }
 
 async fn handle_coordinator(stats: Arc<Mutex<ServerStats>>, req_id: String) {
@@ -155,6 +341,9 @@ async fn handle_coordinator(stats: Arc<Mutex<ServerStats>>, req_id: String) {
         // Entry was removed (if it existed)
     }
 
+    data.election_replies.remove(&req_id);
+    data.reply_notifiers.remove(&req_id);
+
     if data.elections_initiated_by_me.remove(&req_id) {
         // Entry was removed (if it existed)
     }
@@ -162,6 +351,71 @@ async fn handle_coordinator(stats: Arc<Mutex<ServerStats>>, req_id: String) {
     if data.requests_buffer.remove(&req_id).is_some() {
         // Entry was removed (if it existed)
     }
+
+    if data.election_ages.remove(&req_id).is_some() {
+        data.election_metrics.completed += 1;
+    }
+}
+
+// Restarts or abandons elections whose coordinator never showed up, e.g. because
+// it died or its broadcast was lost.
+async fn sweep_elections(
+    service_socket: Arc<UdpSocket>,
+    election_socket: Arc<UdpSocket>,
+    stats: Arc<Mutex<ServerStats>>,
+) {
+    let mut data = stats.lock().await;
+    if data.down {
+        return;
+    }
+    let timeout = Duration::from_millis(ELECTION_TIMEOUT_MILLIS);
+    let stale: Vec<(String, u32)> = data
+        .election_ages
+        .iter()
+        .filter(|(_, age)| age.started.elapsed() > timeout)
+        .map(|(req_id, age)| (req_id.clone(), age.restarts))
+        .collect();
+    if stale.is_empty() {
+        return;
+    }
+
+    let mut restart = Vec::new();
+    for (req_id, restarts) in stale {
+        data.running_elections.remove(&req_id);
+        data.elections_received_oks.remove(&req_id);
+        data.elections_initiated_by_me.remove(&req_id);
+        data.election_replies.remove(&req_id);
+        data.reply_notifiers.remove(&req_id);
+        if data.requests_buffer.contains_key(&req_id) && restarts < MAX_ELECTION_RESTARTS {
+            println!("[{}] Stale election, restarting", req_id);
+            data.election_ages.insert(
+                req_id.clone(),
+                ElectionAge {
+                    started: Instant::now(),
+                    restarts: restarts + 1,
+                },
+            );
+            data.election_metrics.restarted += 1;
+            restart.push(req_id);
+        } else {
+            println!("[{}] Stale election, abandoning", req_id);
+            data.requests_buffer.remove(&req_id);
+            data.election_ages.remove(&req_id);
+            data.election_metrics.abandoned += 1;
+        }
+    }
+    println!("Election metrics {:?}", data.election_metrics);
+    drop(data);
+
+    for req_id in restart {
+        tokio::spawn(send_election_msg(
+            service_socket.clone(),
+            election_socket.clone(),
+            stats.clone(),
+            req_id,
+            true,
+        ));
+    }
 }
 
 async fn broadcast_coordinator(
@@ -196,25 +450,24 @@ async fn send_election_msg(
         let sender = election_socket.local_addr().unwrap();
         println!("[{}] Sending Election msgs! - {}", req_id, init_f);
 
-        let peer_servers = data.get_peer_servers();
-        let mut sys = System::new_with_specifics(
-            RefreshKind::new()
-                .with_cpu(CpuRefreshKind::everything())
-                .with_memory(),
-        );
-        sys.refresh_cpu();
-        let priority = 16.0 - sys.load_average().one as f32;
+        let peer_servers = data.live_peer_servers();
+        let priority = data.priority();
+        let load = data.load;
         let own_priority = data
             .running_elections
             .entry(req_id.clone())
             .or_insert(priority)
             .to_owned();
+        data.touch_election(&req_id);
 
         if !data.elections_initiated_by_me.contains(&req_id) {
             data.elections_initiated_by_me.insert(req_id.clone());
         }
         drop(data);
-        println!("[{}] My own Priority {} - {}", req_id, own_priority, init_f);
+        println!(
+            "[{}] My own Priority {} ({}) - {}",
+            req_id, own_priority, load, init_f
+        );
 
         for server in &peer_servers {
             let msg = Msg {
@@ -231,18 +484,17 @@ async fn send_election_msg(
                 .unwrap();
         }
         println!("[{}] Waiting for ok msg - {}", req_id, init_f);
+        let started = Instant::now();
         let sleep = sleep(Duration::from_millis(500));
         tokio::pin!(sleep);
         // sleep(Duration::from_millis(1000)).await;
 
         tokio::select! {
             _ = &mut sleep => {
-                // Code to execute when sleep completes
-                println!("Sleep completed");
+                println!("[{}] Not every peer answered within {:?}", req_id, started.elapsed());
             },
-            _ = check_for_oks(stats.clone(), req_id.clone()) => {
-                // Code to execute when check_for_oks completes
-                println!("check_for_oks completed");
+            _ = wait_for_replies(stats.clone(), req_id.clone(), peer_servers.len()) => {
+                println!("[{}] Answers after {:?}", req_id, started.elapsed());
             }
         }
 
@@ -250,27 +502,62 @@ async fn send_election_msg(
 
         if !data.elections_received_oks.contains(&req_id) {
             println!("[{}] Did not find ok msgs - {}", req_id, init_f);
+            // without a majority the other side of a partition may be electing too
+            let servers = data.election_replies.get(&req_id).map_or(0, HashSet::len) + 1;
+            if data.quorum && servers < data.majority() {
+                println!(
+                    "[{}] No quorum ({} of {} servers), not coordinating",
+                    req_id,
+                    servers,
+                    data.peer_servers.len() + 1
+                );
+                return;
+            }
             drop(data);
             let own_ip = election_socket.local_addr().unwrap().to_string();
-            broadcast_coordinator(
-                election_socket.clone(),
-                own_ip,
-                peer_servers,
-                req_id.clone(),
-            )
-            .await;
-            reply_to_client(service_socket.clone(), req_id.clone(), stats.clone()).await;
+            let mut data = stats.lock().await;
+            if data.lease_mode {
+                println!("[{}] Taking the lease", req_id);
+                data.grant_lease(election_socket.local_addr().unwrap());
+            }
+            drop(data);
+            // a request is completed once it is served, so the others only hear
+            // of it then; if we never got it, their elections are restarted
+            if reply_to_client(service_socket.clone(), req_id.clone(), stats.clone()).await {
+                stats.lock().await.complete(&req_id);
+                broadcast_coordinator(
+                    election_socket.clone(),
+                    own_ip,
+                    peer_servers,
+                    req_id.clone(),
+                )
+                .await;
+            }
             handle_coordinator(stats.clone(), req_id.clone()).await;
         }
     }
 }
 
-async fn check_for_oks(stats: Arc<Mutex<ServerStats>>, req_id: String) {
+// Resolves once an OK for req_id has arrived or all `expected` peers have
+// answered, woken by handle_election_reply.
+async fn wait_for_replies(stats: Arc<Mutex<ServerStats>>, req_id: String, expected: usize) {
     loop {
-        match stats.lock().await.elections_received_oks.contains(&req_id) {
-            true => break,
-            false => continue,
+        let mut data = stats.lock().await;
+        let replied = data
+            .reply_notifiers
+            .entry(req_id.clone())
+            .or_default()
+            .clone();
+        // register before checking, so an answer arriving in between is not missed
+        let notified = replied.notified();
+        tokio::pin!(notified);
+        notified.as_mut().enable();
+        let replies = data.election_replies.get(&req_id).map_or(0, HashSet::len);
+        if data.elections_received_oks.contains(&req_id) || replies >= expected {
+            return;
         }
+        drop(data);
+        notified.await;
     }
 }
 
@@ -284,9 +571,51 @@ async fn handle_client(
     let req_id = format!("{}:{}", msg.sender, id);
     println!("[{}] Handling client Request", req_id);
     let mut data = stats.lock().await;
+    if data.is_completed(&req_id) {
+        drop(data);
+        println!("[{}] Replay of a served request, refusing", req_id);
+        refuse_client(&service_socket, msg.sender, "request id already served").await;
+        return;
+    }
+    if data.lease_mode {
+        match data.lease_holder() {
+            Some(leader)
+                if leader == election_socket.local_addr().unwrap() && data.has_quorum() =>
+            {
+                let server = data.pick_server();
+                data.complete(&req_id);
+                let servers = data.cloud_servers();
+                drop(data);
+                println!("[{}] Leader assigning request to {}", req_id, server);
+                send_assignment(&service_socket, msg.sender, server, servers).await;
+                return;
+            }
+            Some(leader) if leader == election_socket.local_addr().unwrap() => {
+                drop(data);
+                println!("[{}] Leader without quorum, refusing", req_id);
+                refuse_client(&service_socket, msg.sender, "no quorum").await;
+                return;
+            }
+            // the leader most likely got the request too, a copy covers a lost one
+            Some(leader) if !data.suspected.contains(&leader) => {
+                drop(data);
+                println!("[{}] Forwarding request to leader {}", req_id, leader);
+                send_to_peer(
+                    &election_socket,
+                    leader,
+                    Type::ClientRequestForward(msg.sender, id),
+                )
+                .await;
+                return;
+            }
+            // no leader, or it is suspected dead: the election for this request picks one
+            _ => {}
+        }
+    }
     data.requests_buffer
         .entry(req_id.clone())
         .or_insert_with(|| msg.clone());
+    data.touch_election(&req_id);
 
     if data.running_elections.contains_key(&req_id) {
         println!(
@@ -310,6 +639,506 @@ async fn handle_client(
     }
 }
 
+// Sends a heartbeat to every peer, and suspects the peers that have been silent
+// for too long.
+async fn send_heartbeats(socket: Arc<UdpSocket>, stats: Arc<Mutex<ServerStats>>) {
+    let mut data = stats.lock().await;
+    if data.down {
+        return;
+    }
+    let timeout = Duration::from_millis(SUSPECT_AFTER_MILLIS);
+    for server in data.get_peer_servers() {
+        let silent = match data.last_heartbeats.get(&server.1) {
+            Some(heard) => heard.elapsed() > timeout,
+            None => true,
+        };
+        if silent && data.suspected.insert(server.1) {
+            println!("Suspecting {} dead", server.1);
+        }
+    }
+    let peer_servers = data.get_peer_servers();
+    drop(data);
+
+    for server in &peer_servers {
+        let msg = Msg {
+            sender: socket.local_addr().unwrap(),
+            receiver: server.1,
+            msg_type: Type::Heartbeat,
+            payload: None,
+        };
+        let serialized_msg = serde_cbor::ser::to_vec(&msg).unwrap();
+        socket.send_to(&serialized_msg, server.1).await.unwrap();
+    }
+}
+
+// A server joining through us gets every member. With the replicated log it is
+// added once its config entry commits, in gossip mode the other members get
+// the news from us. Only the server itself takes back one that left, news of
+// it may be older than its leave.
+async fn handle_server_join(
+    peer: (SocketAddr, SocketAddr, SocketAddr),
+    src_addr: SocketAddr,
+    socket: Arc<UdpSocket>,
+    stats: Arc<Mutex<ServerStats>>,
+    log: Arc<Mutex<ReplicatedLog>>,
+    dir_of_service: Arc<Mutex<ServerDirOfService>>,
+) {
+    let mut data = stats.lock().await;
+    let members = data.members();
+    if !data.gossip {
+        drop(data);
+        send_to_peer(&socket, peer.1, Type::Membership(members)).await;
+        let command = Command::AddServer(peer.0, peer.1, peer.2);
+        step_log(&log, &socket, &dir_of_service, &stats, |log| {
+            log.propose(command)
+        })
+        .await;
+        return;
+    }
+    if src_addr == peer.1 {
+        data.left.remove(&peer.1);
+    }
+    let added = data.add_peer(peer);
+    let peers = data.get_peer_servers();
+    drop(data);
+
+    if added {
+        println!("Server {} joined", peer.1);
+        // forwarded once by each member that did not know it yet
+        for other in peers.iter().filter(|other| other.1 != peer.1) {
+            send_to_peer(&socket, other.1, Type::ServerJoin(peer.0, peer.1, peer.2)).await;
+        }
+    }
+    if src_addr == peer.1 {
+        send_to_peer(&socket, peer.1, Type::Membership(members)).await;
+    }
+}
+
+// The members, in reply to our join. The directory comes through the
+// replicated log, or in gossip mode from the members' states.
+async fn handle_membership(
+    members: Vec<(SocketAddr, SocketAddr, SocketAddr)>,
+    socket: Arc<UdpSocket>,
+    stats: Arc<Mutex<ServerStats>>,
+    log: Arc<Mutex<ReplicatedLog>>,
+) {
+    let mut data = stats.lock().await;
+    if !data.joining {
+        return;
+    }
+    for member in members {
+        data.add_peer(member);
+    }
+    println!("Joined, peers {:?}", data.peer_servers);
+    data.joining = false;
+    let gossip = data.gossip;
+    let own_ips = data.own_ips.unwrap();
+    let peers = data.get_peer_servers();
+    drop(data);
+    if gossip {
+        // from us, so that members that saw us leave before take us back
+        for peer in &peers {
+            send_to_peer(
+                &socket,
+                peer.1,
+                Type::ServerJoin(own_ips.0, own_ips.1, own_ips.2),
+            )
+            .await;
+        }
+        query_dir_states(&socket, &stats).await;
+    } else {
+        // where the log's config entries start from
+        log.lock()
+            .await
+            .set_peers(peers.iter().map(|peer| peer.1).collect());
+    }
+}
+
+// With the replicated log the server is removed once its config entry commits,
+// in gossip mode the other members get the news from us.
+async fn handle_server_leave(
+    election_addr: SocketAddr,
+    socket: Arc<UdpSocket>,
+    stats: Arc<Mutex<ServerStats>>,
+    log: Arc<Mutex<ReplicatedLog>>,
+    dir_of_service: Arc<Mutex<ServerDirOfService>>,
+) {
+    let mut data = stats.lock().await;
+    if !data.gossip {
+        drop(data);
+        let command = Command::RemoveServer(election_addr);
+        step_log(&log, &socket, &dir_of_service, &stats, |log| {
+            log.propose(command)
+        })
+        .await;
+        return;
+    }
+    if !data.remove_peer(election_addr) {
+        return;
+    }
+    let peers = data.get_peer_servers();
+    drop(data);
+
+    println!("Server {} left", election_addr);
+    for peer in &peers {
+        send_to_peer(&socket, peer.1, Type::ServerLeave(election_addr)).await;
+    }
+}
+
+// A committed config entry: the server is added to (or removed from) the ones
+// we heartbeat, suspect and tell clients about.
+async fn apply_membership(command: &Command, stats: &Arc<Mutex<ServerStats>>) {
+    let mut data = stats.lock().await;
+    match *command {
+        Command::AddServer(service, election_addr, send) => {
+            data.left.remove(&election_addr);
+            if data.add_peer((service, election_addr, send)) {
+                println!("Server {} joined", election_addr);
+            }
+        }
+        Command::RemoveServer(election_addr) if data.remove_peer(election_addr) => {
+            println!("Server {} left", election_addr);
+        }
+        _ => {}
+    }
+}
+
+// Asks a member to let us in, until the members arrive.
+async fn join_cloud(contact: SocketAddr, socket: Arc<UdpSocket>, stats: Arc<Mutex<ServerStats>>) {
+    let own_ips = stats.lock().await.own_ips.unwrap();
+    while stats.lock().await.joining {
+        println!("Joining through {}", contact);
+        send_to_peer(
+            &socket,
+            contact,
+            Type::ServerJoin(own_ips.0, own_ips.1, own_ips.2),
+        )
+        .await;
+        sleep(Duration::from_millis(JOIN_RETRY_MILLIS)).await;
+    }
+}
+
+// Tells every member we are leaving, on shutdown.
+async fn leave_cloud(socket: &Arc<UdpSocket>, stats: &Arc<Mutex<ServerStats>>) {
+    let data = stats.lock().await;
+    let own_addr = data.own_ips.unwrap().1;
+    let peers = data.get_peer_servers();
+    drop(data);
+    println!("Leaving the cloud");
+    for peer in peers {
+        send_to_peer(socket, peer.1, Type::ServerLeave(own_addr)).await;
+    }
+}
+
+// Whether a majority of the servers, ourselves included, acknowledges a probe
+// right now. Suspicion lags a partition by up to SUSPECT_AFTER_MILLIS, so it is
+// not enough to let a minority change state.
+async fn confirm_quorum(socket: &Arc<UdpSocket>, stats: &Arc<Mutex<ServerStats>>) -> bool {
+    let mut data = stats.lock().await;
+    if !data.has_quorum() {
+        return false;
+    }
+    let needed = data.majority() - 1;
+    if !data.quorum || needed == 0 {
+        return true;
+    }
+    let nonce = rand::thread_rng().gen::<u64>();
+    let acked = Arc::new(Notify::new());
+    data.quorum_probes
+        .insert(nonce, (HashSet::new(), acked.clone()));
+    let peer_servers = data.get_peer_servers();
+    drop(data);
+
+    for server in &peer_servers {
+        send_to_peer(socket, server.1, Type::QuorumProbe(nonce)).await;
+    }
+    let deadline = Instant::now() + Duration::from_millis(QUORUM_PROBE_MILLIS);
+    loop {
+        // register before checking, so an ack arriving in between is not missed
+        let notified = acked.notified();
+        tokio::pin!(notified);
+        notified.as_mut().enable();
+        let mut data = stats.lock().await;
+        let acks = data
+            .quorum_probes
+            .get(&nonce)
+            .map_or(0, |(acks, _)| acks.len());
+        if acks >= needed || Instant::now() >= deadline {
+            data.quorum_probes.remove(&nonce);
+            return acks >= needed;
+        }
+        drop(data);
+        let _ = tokio::time::timeout_at(deadline, notified).await;
+    }
+}
+
+fn handle_quorum_ack(data: &mut ServerStats, nonce: u64, src_addr: SocketAddr) {
+    let member = data.peer_servers.iter().any(|peer| peer.1 == src_addr);
+    if let Some((acks, acked)) = data.quorum_probes.get_mut(&nonce) {
+        if member && acks.insert(src_addr) {
+            acked.notify_waiters();
+        }
+    }
+}
+
+// A suspected peer that is heard from again catches up on the updates it
+// missed through the replicated log, or in gossip mode by swapping states with
+// us right away.
+async fn handle_heartbeat(
+    src_addr: SocketAddr,
+    socket: Arc<UdpSocket>,
+    stats: Arc<Mutex<ServerStats>>,
+    dir_of_service: Arc<Mutex<ServerDirOfService>>,
+) {
+    let mut data = stats.lock().await;
+    data.last_heartbeats.insert(src_addr, Instant::now());
+    if !data.suspected.remove(&src_addr) {
+        return;
+    }
+    println!("{} recovered", src_addr);
+    let gossip = data.gossip;
+    drop(data);
+    if gossip {
+        let state = dir_of_service.lock().await.state().await;
+        send_dir_state(&socket, src_addr, state).await;
+        send_to_peer(&socket, src_addr, Type::DirStateQuery).await;
+    }
+}
+
+// Runs one step of the replicated log, sends what it has to say to the peers
+// and applies what it has newly committed. Applying under the log's lock keeps
+// the commands in log order.
+async fn step_log<F>(
+    log: &Arc<Mutex<ReplicatedLog>>,
+    socket: &Arc<UdpSocket>,
+    dir_of_service: &Arc<Mutex<ServerDirOfService>>,
+    stats: &Arc<Mutex<ServerStats>>,
+    step: F,
+) where
+    F: FnOnce(&mut ReplicatedLog) -> Vec<(SocketAddr, Type)>,
+{
+    let mut log = log.lock().await;
+    let outbox = step(&mut log);
+    let committed = log.take_committed();
+    if !committed.is_empty() {
+        let mut dir_of_service = dir_of_service.lock().await;
+        for (id, command) in committed {
+            apply_membership(&command, stats).await;
+            if let Err(e) = dir_of_service.apply(command, Some(id)).await {
+                println!("Skipping a committed update: {}", e);
+            }
+        }
+        // every server applies the same log, no stale copy can come back
+        dir_of_service.collect_delivered(&[]).await;
+    }
+    drop(log);
+
+    for (target, msg_type) in outbox {
+        send_to_peer(socket, target, msg_type).await;
+    }
+}
+
+async fn send_to_peer(socket: &Arc<UdpSocket>, target: SocketAddr, msg_type: Type) {
+    let msg = Msg {
+        sender: socket.local_addr().unwrap(),
+        receiver: target,
+        msg_type,
+        payload: None,
+    };
+    let serialized_msg = serde_cbor::ser::to_vec(&msg).unwrap();
+    if let Err(e) = socket.send_to(&serialized_msg, target).await {
+        println!("Could not send to {}: {}", target, e);
+    }
+}
+
+// Gossip mode: sends a directory state in parts that fit in a datagram.
+async fn send_dir_state(socket: &Arc<UdpSocket>, target: SocketAddr, state: DirState) {
+    for part in split_state(state, DIR_STATE_PART_BYTES) {
+        send_to_peer(socket, target, Type::DirState(part)).await;
+    }
+}
+
+// A client's update: applied right away in gossip mode, where anti-entropy
+// spreads it, appended to the replicated log otherwise. Broadcast updates were
+// sent to every server, `request` tells the copies apart from other updates,
+// see ReplicatedLog::propose_broadcast.
+async fn submit_update(
+    command: Command,
+    broadcast: bool,
+    request: Option<String>,
+    stats: &Arc<Mutex<ServerStats>>,
+    log: &Arc<Mutex<ReplicatedLog>>,
+    socket: &Arc<UdpSocket>,
+    dir_of_service: &Arc<Mutex<ServerDirOfService>>,
+) {
+    if stats.lock().await.gossip {
+        if let Err(e) = dir_of_service.lock().await.apply(command, request).await {
+            println!("Skipping an update: {}", e);
+        }
+    } else if broadcast {
+        step_log(log, socket, dir_of_service, stats, |log| {
+            log.propose_broadcast(request, command)
+        })
+        .await;
+    } else {
+        step_log(log, socket, dir_of_service, stats, |log| {
+            log.propose(command)
+        })
+        .await;
+    }
+}
+
+// A client message that changes the directory or the view grants. The minority
+// side of a partition must not diverge, so a majority has to acknowledge first.
+async fn handle_state_change(
+    msg_type: Type,
+    src_addr: SocketAddr,
+    request: Option<String>,
+    service_socket: Arc<UdpSocket>,
+    election_socket: Arc<UdpSocket>,
+    stats: Arc<Mutex<ServerStats>>,
+    log: Arc<Mutex<ReplicatedLog>>,
+    dir_of_service: Arc<Mutex<ServerDirOfService>>,
+    keyring: Arc<Mutex<Keyring>>,
+) {
+    if !confirm_quorum(&election_socket, &stats).await {
+        println!("No quorum, refusing update from {}", src_addr);
+        refuse_client(&service_socket, src_addr, "no quorum").await;
+        return;
+    }
+    let command = match msg_type {
+        Type::DirOfServJoin => Command::Join(src_addr),
+        Type::DirOfServLeave => Command::Leave(src_addr),
+        // a malformed id must not get into the log
+        Type::UpdateAccess(img_id, _) if parse_img_id(&img_id).is_none() => {
+            println!("Malformed image id {} from {}", img_id, src_addr);
+            refuse_client(&service_socket, src_addr, "malformed image id").await;
+            return;
+        }
+        Type::UpdateAccess(img_id, action) => Command::AccessUpdate(img_id, action),
+        Type::ViewGrant((grant, remaining)) => {
+            if accept_view_grant(&grant, &keyring).await {
+                let dir_of_service = dir_of_service.lock().await;
+                dir_of_service.set_view_grant(grant, remaining).await;
+            }
+            return;
+        }
+        Type::ViewTokenRequest(img_id, nonce) => {
+            // the members as of now, servers come and go
+            let peer_servers = stats.lock().await.get_peer_servers();
+            handle_view_token_request(
+                img_id,
+                nonce,
+                service_socket,
+                src_addr,
+                dir_of_service,
+                peer_servers,
+            )
+            .await;
+            return;
+        }
+        _ => return,
+    };
+    submit_update(
+        command,
+        true,
+        request,
+        &stats,
+        &log,
+        &election_socket,
+        &dir_of_service,
+    )
+    .await;
+}
+
+// Gossip mode: sends our directory state to a random live peer, which merges it,
+// and asks for its state for us to merge.
+async fn anti_entropy_round(
+    socket: Arc<UdpSocket>,
+    stats: Arc<Mutex<ServerStats>>,
+    dir_of_service: Arc<Mutex<ServerDirOfService>>,
+) {
+    let data = stats.lock().await;
+    if data.down {
+        return;
+    }
+    let peers = data.live_peer_servers();
+    // suspected peers too, they have to see a delivery before it is forgotten
+    let replicas: Vec<SocketAddr> = data.get_peer_servers().iter().map(|p| p.1).collect();
+    drop(data);
+    let dir_of_service = dir_of_service.lock().await;
+    dir_of_service.collect_delivered(&replicas).await;
+    if peers.is_empty() {
+        return;
+    }
+    let peer = peers[rand::thread_rng().gen_range(0..peers.len())].1;
+    let state = dir_of_service.state().await;
+    drop(dir_of_service);
+    send_dir_state(&socket, peer, state).await;
+    send_to_peer(&socket, peer, Type::DirStateQuery).await;
+}
+
+// Gossip mode: asks every peer for its directory state, all the replies are
+// merged into ours.
+async fn query_dir_states(socket: &Arc<UdpSocket>, stats: &Arc<Mutex<ServerStats>>) {
+    let peers = stats.lock().await.get_peer_servers();
+    for peer in peers {
+        send_to_peer(socket, peer.1, Type::DirStateQuery).await;
+    }
+}
+
+// Lease mode: the leader renews its lease with every peer, and learns their
+// priorities from the replies.
+async fn renew_lease(socket: Arc<UdpSocket>, stats: Arc<Mutex<ServerStats>>) {
+    let own_addr = socket.local_addr().unwrap();
+    let mut data = stats.lock().await;
+    let priority = data.priority();
+    if data.down || data.lease_holder() != Some(own_addr) {
+        return;
+    }
+    if !data.has_quorum() {
+        println!("Lost the quorum, giving up the lease");
+        data.lease = None;
+        return;
+    }
+    data.grant_lease(own_addr);
+    data.loads.insert(own_addr, (priority, Instant::now()));
+    let peer_servers = data.get_peer_servers();
+    drop(data);
+
+    for server in &peer_servers {
+        let msg = Msg {
+            sender: own_addr,
+            receiver: server.1,
+            msg_type: Type::LeaderLease(priority),
+            payload: None,
+        };
+        let serialized_msg = serde_cbor::ser::to_vec(&msg).unwrap();
+        socket.send_to(&serialized_msg, server.1).await.unwrap();
+    }
+}
+
+async fn handle_lease(leader: SocketAddr, socket: Arc<UdpSocket>, stats: Arc<Mutex<ServerStats>>) {
+    let own_addr = socket.local_addr().unwrap();
+    let mut data = stats.lock().await;
+    let priority = data.priority();
+    // two leaders once a partition heals: the higher address keeps its lease
+    if data.lease_holder() == Some(own_addr) && own_addr.to_string() > leader.to_string() {
+        return;
+    }
+    data.grant_lease(leader);
+    drop(data);
+
+    let msg = Msg {
+        sender: own_addr,
+        receiver: leader,
+        msg_type: Type::LoadReport(priority),
+        payload: None,
+    };
+    let serialized_msg = serde_cbor::ser::to_vec(&msg).unwrap();
+    socket.send_to(&serialized_msg, leader).await.unwrap();
+}
+
 async fn handle_elec_request(
     buffer: &[u8],
     src_addr: std::net::SocketAddr,
@@ -317,6 +1146,8 @@ async fn handle_elec_request(
     election_socket: Arc<UdpSocket>,
     stats: &Arc<Mutex<ServerStats>>,
     dir_of_service: Arc<Mutex<ServerDirOfService>>,
+    log: Arc<Mutex<ReplicatedLog>>,
+    keyring: Arc<Mutex<Keyring>>,
 ) {
     // let request: &str = std::str::from_utf8(buffer).expect("Failed to convert to UTF-8");
     // let msg: Msg = match serde_json::from_str(request) {
@@ -329,7 +1160,10 @@ async fn handle_elec_request(
         Err(e) => return,
     };
 
-    println!("{:?}", msg);
+    // heartbeats arrive from every peer twice a second
+    if !matches!(msg.msg_type, Type::Heartbeat) {
+        println!("{:?}", msg);
+    }
 
     match msg.msg_type {
         Type::ClientRequest(req_id) => {
@@ -342,6 +1176,21 @@ async fn handle_elec_request(
             )
             .await;
         }
+        // the copy of a request we already served is not a replay to refuse, and
+        // a copy is not passed on again if we are not the leader any more
+        Type::ClientRequestForward(client, id) => {
+            let data = stats.lock().await;
+            let leading = data.lease_holder() == Some(election_socket.local_addr().unwrap());
+            let served = data.is_completed(&format!("{}:{}", client, id));
+            drop(data);
+            if leading && !served {
+                let msg = Msg {
+                    sender: client,
+                    ..msg
+                };
+                handle_client(id, msg, service_socket, election_socket, stats.to_owned()).await;
+            }
+        }
         Type::ElectionRequest(priority) => {
             let req_id = msg.payload.clone().unwrap();
             println!("[{}] handling election!", req_id);
@@ -355,77 +1204,251 @@ async fn handle_elec_request(
             )
             .await;
         }
-        Type::CoordinatorBrdCast(_coordinator_ip) => {
+        Type::CoordinatorBrdCast(coordinator_ip) => {
             let req_id = msg.payload.clone().unwrap();
             println!("[{}] Handlign Broadcast!", req_id);
-            handle_coordinator(stats.to_owned(), req_id).await;
+            let mut data = stats.lock().await;
+            if data.lease_mode {
+                if let Ok(leader) = coordinator_ip.parse() {
+                    data.grant_lease(leader);
+                }
+            }
+            drop(data);
+            handle_coordinator(stats.to_owned(), req_id.clone()).await;
+            stats.lock().await.complete(&req_id);
+        }
+        Type::InjectFault(order) => {
+            if accept_fault_order(&order, &mut *stats.lock().await) {
+                match Fault::parse(order.value.fault.as_str()) {
+                    Some(fault) => inject_fault(fault, election_socket, stats.to_owned()).await,
+                    None => println!("Invalid fault from {}: {}", src_addr, order.value.fault),
+                }
+            } else {
+                println!("Refusing fault from {}: {}", src_addr, order.value.fault);
+            }
+        }
+        Type::Heartbeat => {
+            handle_heartbeat(src_addr, election_socket, stats.to_owned(), dir_of_service).await;
+        }
+        Type::QuorumProbe(nonce) => {
+            send_to_peer(&election_socket, src_addr, Type::QuorumAck(nonce)).await;
+        }
+        Type::QuorumAck(nonce) => {
+            handle_quorum_ack(&mut *stats.lock().await, nonce, src_addr);
+        }
+        Type::LeaderLease(_priority) => {
+            handle_lease(src_addr, election_socket, stats.to_owned()).await;
+        }
+        Type::LoadReport(priority) => {
+            stats
+                .lock()
+                .await
+                .loads
+                .insert(src_addr, (priority, Instant::now()));
         }
         Type::OKMsg(_priority) => {
             let req_id = msg.payload.clone().unwrap();
             println!("[{}] Handling OK", req_id);
-            handle_ok_msg(req_id, stats.to_owned()).await;
+            handle_ok_msg(req_id, src_addr, stats.to_owned()).await;
         }
-        Type::Fail(fail_time) => {
-            println!("Will fail for {}", fail_time);
-            handle_fail_msg(fail_time, election_socket.clone(), &stats.clone()).await;
+        Type::ElectionAck => {
+            let req_id = msg.payload.clone().unwrap();
+            handle_election_reply(&mut *stats.lock().await, req_id, src_addr);
         }
-        Type::DirOfServQuery => {
-            dir_of_service
-                .lock()
-                .await
-                .query_reply(election_socket.clone(), src_addr)
-                .await;
+        Type::LogVoteRequest(..)
+        | Type::LogVote(..)
+        | Type::LogAppend(..)
+        | Type::LogAppendReply(..)
+        | Type::LogForward(..) => {
+            step_log(&log, &election_socket, &dir_of_service, stats, |log| {
+                log.handle(src_addr, msg.msg_type)
+            })
+            .await;
+        }
+        Type::ServerJoin(ip_service, ip_elec, ip_send) => {
+            handle_server_join(
+                (ip_service, ip_elec, ip_send),
+                src_addr,
+                election_socket,
+                stats.to_owned(),
+                log,
+                dir_of_service,
+            )
+            .await;
+        }
+        Type::Membership(members) => {
+            handle_membership(members, election_socket, stats.to_owned(), log).await;
+        }
+        Type::ServerLeave(election_addr) => {
+            handle_server_leave(
+                election_addr,
+                election_socket,
+                stats.to_owned(),
+                log,
+                dir_of_service,
+            )
+            .await;
         }
-        Type::DirOfServQueryReply(d) => dir_of_service.lock().await.update(d).await,
-        Type::ServerDirOfServQueryPending => {
+        Type::DirStateQuery => {
+            let state = dir_of_service.lock().await.state().await;
+            send_dir_state(&election_socket, src_addr, state).await;
+        }
+        Type::DirState(state) => dir_of_service.lock().await.merge(src_addr, state).await,
+        Type::ViewGrant((grant, remaining)) if accept_view_grant(&grant, &keyring).await => {
             dir_of_service
                 .lock()
                 .await
-                .server_query_pending_reply(election_socket.clone(), src_addr)
-                .await;
-        }
-        Type::ServerDirOfServQueryPendingReply(d) => {
-            dir_of_service.lock().await.update_pending_requests(d).await
+                .set_view_grant(grant, remaining)
+                .await
         }
         _ => {}
     }
 }
 
-async fn send_fail_msg(socket: Arc<UdpSocket>, stats: &Arc<Mutex<ServerStats>>) {
-    let peer_servres: Vec<(SocketAddr, SocketAddr, SocketAddr)> =
-        stats.lock().await.get_peer_servers();
-
-    let next_server = peer_servres[{
-        let mut rng = rand::thread_rng();
-        rng.gen_range(0..peer_servres.len())
-    } as usize]
-        .1;
-    let fail_msg = Msg {
-        msg_type: Type::Fail(60),
-        sender: socket.local_addr().unwrap(),
-        receiver: next_server,
-        payload: None,
+// Online views: a grant is kept only if the owner's key signed it. The key is
+// pinned the first time a grant for one of the owner's images comes in.
+async fn accept_view_grant(grant: &Signed<OnlineGrant>, keyring: &Mutex<Keyring>) -> bool {
+    let owner = match grant
+        .value
+        .img_id
+        .split('&')
+        .next()
+        .map(|owner| owner.parse())
+    {
+        Some(Ok(owner)) => owner,
+        _ => return false,
     };
-
-    let fail_msg = serde_cbor::ser::to_vec(&fail_msg).unwrap();
-    // serde_json::to_string(&fail_msg).unwrap();
-    socket
-        .send_to(&fail_msg, next_server.to_string())
-        .await
-        .expect("Failed to send!");
+    let accepted = grant.verify(&grant.value.owner_key)
+        && keyring.lock().await.pin(owner, grant.value.owner_key);
+    if !accepted {
+        println!(
+            "Rejecting view grant for {}: bad signature",
+            grant.value.img_id
+        );
+    }
+    accepted
 }
 
-async fn handle_fail_msg(fail_time: u32, socket: Arc<UdpSocket>, stats: &Arc<Mutex<ServerStats>>) {
-    let sleep_time = Duration::from_secs(fail_time as u64);
-    stats.lock().await.down = true;
-    sleep(sleep_time).await;
+// Back from being down: our view of the peers is stale, and so is our state.
+// It catches up through the replicated log, or in gossip mode by merging
+// every peer's state into ours, which keeps what only we had seen.
+async fn wake_up(socket: Arc<UdpSocket>, stats: &Arc<Mutex<ServerStats>>) {
     println!("Woke Up!");
-    stats.lock().await.down = false;
-    send_fail_msg(socket.clone(), stats).await;
+    let mut data = stats.lock().await;
+    data.down = false;
+    data.faults.paused = false;
+    data.reset_failure_detector();
+    let gossip = data.gossip;
+    drop(data);
+
+    if gossip {
+        query_dir_states(&socket, stats).await;
+    }
+}
 
-    ServerDirOfService::query(socket.clone(), stats.lock().await.peer_servers.clone()).await;
-    ServerDirOfService::query_pending(socket.clone(), stats.lock().await.peer_servers.clone())
-        .await;
+// Admin faults are only taken when FAULT_ADMIN_KEY is set, signed with that
+// key, fresh and issued after the last one taken, so none can be replayed.
+fn accept_fault_order(order: &Signed<FaultOrder>, data: &mut ServerStats) -> bool {
+    let now_millis = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64);
+    let issued = order.value.issued_millis;
+    let accepted = data.fault_admin.is_some_and(|key| order.verify(&key))
+        && issued > data.last_fault_order
+        && issued.abs_diff(now_millis) < FAULT_ORDER_MAX_AGE_MILLIS;
+    if accepted {
+        data.last_fault_order = issued;
+    }
+    accepted
+}
+
+async fn inject_fault(fault: Fault, socket: Arc<UdpSocket>, stats: Arc<Mutex<ServerStats>>) {
+    println!("Injecting fault {:?}", fault);
+    match fault {
+        Fault::Crash => {
+            println!("Crashing");
+            std::process::exit(1);
+        }
+        Fault::Pause(secs) => {
+            let mut data = stats.lock().await;
+            data.down = true;
+            data.faults.paused = true;
+            drop(data);
+            sleep(Duration::from_secs(secs)).await;
+            wake_up(socket, &stats).await;
+        }
+        fault => {
+            let mut data = stats.lock().await;
+            let addrs = match &fault {
+                Fault::Partition(peer) => match data.peer_servers.iter().find(|p| p.1 == *peer) {
+                    Some((ip_service, ip_elec, ip_send)) => vec![*ip_service, *ip_elec, *ip_send],
+                    None => vec![*peer],
+                },
+                _ => Vec::new(),
+            };
+            data.faults.apply(fault, addrs);
+        }
+    }
+}
+
+// Answers a view token request for an offline owner, from the share's recipient
+// only. A granted view is reported to the peer servers and to the owner, so the
+// counts stay in line.
+async fn handle_view_token_request(
+    img_id: String,
+    nonce: u64,
+    socket: Arc<UdpSocket>,
+    src_addr: SocketAddr,
+    dir_of_service: Arc<Mutex<ServerDirOfService>>,
+    peers: Vec<(SocketAddr, SocketAddr, SocketAddr)>,
+) {
+    // the request comes from the recipient's cloud socket, next to its client socket
+    let recipient = SocketAddr::new(src_addr.ip(), src_addr.port() + 1);
+    let token = if img_id.split('&').nth(1) == Some(recipient.to_string().as_str()) {
+        dir_of_service
+            .lock()
+            .await
+            .issue_view_token(img_id.as_str())
+            .await
+    } else {
+        println!("{} is not the recipient of {}", src_addr, img_id);
+        None
+    };
+    println!(
+        "View token for {}: {:?}",
+        img_id,
+        token.as_ref().map(|(_, remaining)| remaining)
+    );
+
+    let msg = Msg {
+        sender: socket.local_addr().unwrap(),
+        receiver: src_addr,
+        msg_type: Type::ViewToken(img_id.clone(), nonce, token.clone()),
+        payload: None,
+    };
+    let serialized_msg = serde_cbor::ser::to_vec(&msg).unwrap();
+    socket.send_to(&serialized_msg, src_addr).await.unwrap();
+
+    if let Some(token) = token {
+        let owner = match img_id.split('&').next().unwrap().parse::<SocketAddr>() {
+            Ok(owner) => owner,
+            Err(_) => return,
+        };
+        let targets = peers
+            .iter()
+            .map(|peer| peer.1)
+            .chain(std::iter::once(owner));
+        for target in targets {
+            let msg = Msg {
+                sender: socket.local_addr().unwrap(),
+                receiver: target,
+                msg_type: Type::ViewGrant(token.clone()),
+                payload: None,
+            };
+            let serialized_msg = serde_cbor::ser::to_vec(&msg).unwrap();
+            socket.send_to(&serialized_msg, target).await.unwrap();
+        }
+    }
 }
 
 async fn handle_encryption(
@@ -434,9 +1457,26 @@ async fn handle_encryption(
     src_addr: SocketAddr,
     req_id: String,
     rx: mpsc::Receiver<u32>,
-    default_image: DynamicImage,
+    covers: Covers,
+    pixel_order: PixelOrder,
 ) {
-    let encoded_bytes = encryption::encode_img(data, req_id.clone(), default_image).await;
+    let covers = match covers {
+        Covers::Pool(covers) => covers,
+        Covers::Generated(seed) => {
+            println!("[{}] generating cover with seed {}", req_id, seed);
+            let capacity = data.len() + PART_HEADER_LEN + MANIFEST_LEN;
+            vec![cover_gen::generate_cover(seed, capacity).await]
+        }
+    };
+    let encoded_bytes =
+        match encryption::encode_img(data, req_id.clone(), covers, pixel_order).await {
+            Some(encoded_bytes) => encoded_bytes,
+            None => {
+                println!("[{}] encryption failed", req_id);
+                refuse_client(&socket, src_addr, "encryption failed").await;
+                return;
+            }
+        };
     println!(
         "[{}] finished encryption, image size is {}",
         req_id,
@@ -452,6 +1492,50 @@ async fn handle_encryption(
     .await;
 }
 
+// Tells a client its request will not be served, instead of leaving it waiting.
+async fn refuse_client(socket: &UdpSocket, client: SocketAddr, reason: &str) {
+    let msg = Msg {
+        sender: socket.local_addr().unwrap(),
+        receiver: client,
+        msg_type: Type::Refused(String::from(reason)),
+        payload: None,
+    };
+    let serialized_msg = serde_cbor::ser::to_vec(&msg).unwrap();
+    if let Err(e) = socket.send_to(&serialized_msg, client).await {
+        println!("Failed to refuse request of {}: {}", client, e);
+    }
+}
+
+// An encryption counted in the server's load for as long as it lives, so the
+// load goes back down however the encryption ends, panics included.
+struct InFlight {
+    stats: Arc<Mutex<ServerStats>>,
+    len: u64,
+}
+
+impl InFlight {
+    async fn start(stats: Arc<Mutex<ServerStats>>, len: u64) -> InFlight {
+        let mut data = stats.lock().await;
+        data.load.in_flight_bytes += len;
+        data.load.queue_depth += 1;
+        drop(data);
+        InFlight { stats, len }
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        let stats = self.stats.clone();
+        let len = self.len;
+        // drop cannot wait for the lock
+        tokio::spawn(async move {
+            let mut data = stats.lock().await;
+            data.load.in_flight_bytes -= len;
+            data.load.queue_depth -= 1;
+        });
+    }
+}
+
 async fn startup() {}
 
 #[tokio::main(flavor = "multi_thread", worker_threads = 4)]
@@ -462,11 +1546,55 @@ async fn main() {
     let ip = args[2].as_str();
     let (ip_service, ip_elec, ip_send) = utils::get_ips(ip, mode).await;
 
-    let init_fail: bool = args.len() == 4;
+    // ELECTION_MODE=lease keeps the winner of an election as the leader while its
+    // lease lasts, instead of an election per client request
+    stats.lease_mode = env::var("ELECTION_MODE").is_ok_and(|mode| mode == "lease");
+    if stats.lease_mode {
+        println!("Lease mode, lease of {} ms", LEASE_MILLIS);
+    }
+    // PRIORITY_WEIGHTS=cpu=8,memory=4,bytes=0.5,queue=1 sets what the election
+    // priority is made of (per unit of use, per MiB in flight, per queued job)
+    if let Ok(weights) = env::var("PRIORITY_WEIGHTS") {
+        match PriorityWeights::parse(weights.as_str()) {
+            Some(weights) => stats.weights = weights,
+            None => println!("Invalid PRIORITY_WEIGHTS {}, using the defaults", weights),
+        }
+    }
+    println!("Priority weights {:?}", stats.weights);
+    let pixel_order = utils::get_pixel_order(EMBEDDING_KEY_FILEPATH);
     stats.own_ips = Some((ip_service, ip_elec, ip_send));
-    stats.peer_servers =
-        utils::get_peer_servers(SERVERS_FILEPATH, stats.own_ips.unwrap(), mode).await;
+    stats.mode = String::from(mode);
+    // QUORUM=0 lets any side of a partition coordinate and change state
+    stats.quorum = !env::var("QUORUM").is_ok_and(|quorum| quorum == "0");
+    // REPLICATION=gossip replicates the directory of service by anti-entropy
+    // instead of the replicated log
+    stats.gossip = env::var("REPLICATION").is_ok_and(|replication| replication == "gossip");
+    if stats.gossip {
+        println!(
+            "Gossip replication, anti-entropy every {} ms",
+            ANTI_ENTROPY_MILLIS
+        );
+    }
+    println!(
+        "Quorum {}",
+        if stats.quorum {
+            "required"
+        } else {
+            "not required"
+        }
+    );
+    // JOIN_VIA=<servers.txt entry of a member> joins a running cloud instead of
+    // reading servers.txt
+    let join_via = env::var("JOIN_VIA").ok();
+    match &join_via {
+        Some(_) => stats.joining = true,
+        None => {
+            stats.peer_servers =
+                utils::get_peer_servers(SERVERS_FILEPATH, stats.own_ips.unwrap(), mode).await
+        }
+    }
     println!("{:?}", stats.peer_servers);
+    stats.reset_failure_detector();
 
     let service_socket = Arc::new(UdpSocket::bind(ip_service).await.unwrap());
     let service_socket2 = Arc::clone(&service_socket);
@@ -478,59 +1606,252 @@ async fn main() {
     let send_socket = Arc::new(UdpSocket::bind(ip_send).await.unwrap());
     println!("Server (send back) on {ip_send}");
 
-    let dir_of_service = Arc::new(Mutex::new(ServerDirOfService::new()));
+    let dir_of_service = Arc::new(Mutex::new(ServerDirOfService::new(ip_elec)));
     let dir_of_service2 = Arc::clone(&dir_of_service);
+    // joins, leaves and pending access updates go through the replicated log
+    let log = Arc::new(Mutex::new(ReplicatedLog::load(
+        format!("{}-{}.cbor", REPLICATED_LOG_FILEPATH, ip_elec).as_str(),
+        ip_elec,
+        stats.peer_servers.iter().map(|peer| peer.1).collect(),
+    )));
+    let log_election = Arc::clone(&log);
+    // owners' keys, to check their view grants
+    let keyring = Arc::new(Mutex::new(Keyring::load(
+        format!("{}-{}.cbor", SERVER_KEYRING_FILEPATH, ip_elec).as_str(),
+    )));
+    let keyring_election = Arc::clone(&keyring);
     let stats = Arc::new(Mutex::new(stats));
     let stats_election = Arc::clone(&stats);
+    let stats_service = Arc::clone(&stats);
 
     let mut service_buffer: [u8; BUFFER_SIZE] = [0; BUFFER_SIZE];
-    let mut election_buffer: [u8; 2048] = [0; 2048];
+    // big enough for a log append with its entries
+    let mut election_buffer: [u8; 8192] = [0; 8192];
 
     let mut received_complete_msgs: HashMap<String, BigMessage> = HashMap::new();
     let mut channels_map: HashMap<String, mpsc::Sender<u32>> = HashMap::new();
 
-    let mut img_loader = ImageLoader {
-        def1: None,
-        def2: None,
-        def3: None,
-        def4: None,
-        def5: None,
-        def6: None,
+    // Covers are taken from the cover directory (which can be overridden) unless
+    // COVER_SOURCE=generated, in which case a fresh cover is generated per request
+    let cover_pool = match env::var("COVER_SOURCE").as_deref() {
+        Ok("generated") => {
+            println!("Generating covers per request");
+            None
+        }
+        _ => {
+            let cover_dir = env::var("COVER_IMAGES_DIR").unwrap_or(String::from(COVER_IMAGES_PATH));
+            let cover_pool = CoverPool::new(cover_dir.as_str());
+            println!(
+                "Cover pool {} ready, largest capacity {} bytes",
+                cover_dir,
+                cover_pool.max_capacity()
+            );
+            let cover_pool = Arc::new(Mutex::new(cover_pool));
+            {
+                let cover_pool = cover_pool.clone();
+                tokio::spawn(async move {
+                    loop {
+                        sleep(Duration::from_millis(COVER_RESCAN_MILLIS)).await;
+                        let cover_pool = cover_pool.clone();
+                        let _ = tokio::task::spawn_blocking(move || {
+                            cover_pool.blocking_lock().scan();
+                        })
+                        .await;
+                    }
+                });
+            }
+            Some(cover_pool)
+        }
     };
 
-    //Different def images with different sizes to accomodate different size of secret images
-    // let def1: DynamicImage = image::open("default_images/def1.png").unwrap();
-    // let def2: DynamicImage = image::open("default_images/def2.png").unwrap();
-    // let def3: DynamicImage = image::open("default_images/def3.png").unwrap();
-    // let def4: DynamicImage = image::open("default_images/def4.png").unwrap();
-    // let def5: DynamicImage = image::open("default_images/def5.png").unwrap();
-    // let def6: DynamicImage = image::open("default_images/def6.png").unwrap();
+    if let Some(entry) = join_via {
+        let (_, contact, _) = utils::get_ips(entry.as_str(), mode).await;
+        tokio::spawn(join_cloud(contact, election_socket.clone(), stats.clone()));
+    }
 
-    if init_fail {
-        send_fail_msg(election_socket.clone(), &stats).await;
+    {
+        let election_socket = election_socket.clone();
+        let stats = stats.clone();
+        tokio::spawn(async move {
+            if tokio::signal::ctrl_c().await.is_ok() {
+                leave_cloud(&election_socket, &stats).await;
+                std::process::exit(0);
+            }
+        });
     }
 
-    ServerDirOfService::query(
-        election_socket.clone(),
-        stats.lock().await.peer_servers.clone(),
-    )
-    .await;
+    if stats.lock().await.gossip {
+        query_dir_states(&election_socket, &stats).await;
+        let election_socket = election_socket.clone();
+        let stats = stats.clone();
+        let dir_of_service = dir_of_service.clone();
+        tokio::spawn(async move {
+            loop {
+                sleep(Duration::from_millis(ANTI_ENTROPY_MILLIS)).await;
+                anti_entropy_round(
+                    election_socket.clone(),
+                    stats.clone(),
+                    dir_of_service.clone(),
+                )
+                .await;
+            }
+        });
+    } else {
+        let election_socket = election_socket.clone();
+        let stats = stats.clone();
+        let dir_of_service = dir_of_service.clone();
+        let log = log.clone();
+        tokio::spawn(async move {
+            loop {
+                sleep(Duration::from_millis(LOG_HEARTBEAT_MILLIS)).await;
+                let data = stats.lock().await;
+                // a joining server waits for the members, alone it would lead itself
+                if data.down || data.joining {
+                    continue;
+                }
+                drop(data);
+                step_log(&log, &election_socket, &dir_of_service, &stats, |log| {
+                    log.tick()
+                })
+                .await;
+            }
+        });
+    }
 
-    ServerDirOfService::query_pending(
-        election_socket.clone(),
-        stats.lock().await.peer_servers.clone(),
-    )
-    .await;
+    {
+        let stats = stats.clone();
+        tokio::spawn(async move {
+            let mut sys = System::new_with_specifics(
+                RefreshKind::new()
+                    .with_cpu(CpuRefreshKind::everything())
+                    .with_memory(),
+            );
+            let mut sample = LoadSample::default();
+            loop {
+                sample.sample_system(&mut sys);
+                let mut data = stats.lock().await;
+                data.load.cpu_usage = sample.cpu_usage;
+                data.load.memory_used = sample.memory_used;
+                drop(data);
+                // CPU usage is measured between two refreshes
+                sleep(Duration::from_millis(LOAD_SAMPLE_MILLIS)).await;
+            }
+        });
+    }
+
+    // FAULT_ADMIN_KEY=<admin public key, see fault_admin key> accepts faults
+    // signed by that admin
+    if let Ok(key) = env::var("FAULT_ADMIN_KEY") {
+        match utils::parse_hex_key(key.as_str()) {
+            Some(key) => {
+                println!("Accepting faults from the fault admin");
+                stats.lock().await.fault_admin = Some(key);
+            }
+            None => println!("Invalid FAULT_ADMIN_KEY, refusing admin faults"),
+        }
+    }
+
+    // FAULT_SCHEDULE=<file> injects the faults listed in it, at their time
+    if let Ok(path) = env::var("FAULT_SCHEDULE") {
+        let schedule = faults::load_schedule(path.as_str());
+        println!("Fault schedule {}: {} fault(s)", path, schedule.len());
+        let election_socket = election_socket.clone();
+        let stats = stats.clone();
+        let start = Instant::now();
+        tokio::spawn(async move {
+            for (secs, fault) in schedule {
+                tokio::time::sleep_until(start + Duration::from_secs(secs)).await;
+                tokio::spawn(inject_fault(fault, election_socket.clone(), stats.clone()));
+            }
+        });
+    }
+
+    {
+        let election_socket = election_socket.clone();
+        let stats = stats.clone();
+        tokio::spawn(async move {
+            loop {
+                send_heartbeats(election_socket.clone(), stats.clone()).await;
+                sleep(Duration::from_millis(HEARTBEAT_MILLIS)).await;
+            }
+        });
+    }
+
+    {
+        let service_socket = service_socket.clone();
+        let election_socket = election_socket.clone();
+        let stats = stats.clone();
+        tokio::spawn(async move {
+            loop {
+                sleep(Duration::from_millis(ELECTION_SWEEP_MILLIS)).await;
+                sweep_elections(
+                    service_socket.clone(),
+                    election_socket.clone(),
+                    stats.clone(),
+                )
+                .await;
+            }
+        });
+    }
+
+    if stats.lock().await.lease_mode {
+        let election_socket = election_socket.clone();
+        let stats = stats.clone();
+        tokio::spawn(async move {
+            loop {
+                sleep(Duration::from_millis(LEASE_RENEW_MILLIS)).await;
+                renew_lease(election_socket.clone(), stats.clone()).await;
+            }
+        });
+    }
 
     let h1 = tokio::spawn({
+        let election_socket = election_socket.clone();
         async move {
             loop {
                 match service_socket.recv_from(&mut service_buffer).await {
                     Ok((bytes_read, src_addr)) => {
+                        if stats_service
+                            .lock()
+                            .await
+                            .faults
+                            .should_drop(SocketKind::Service, src_addr)
+                        {
+                            continue;
+                        }
                         println!("{} bytes from {}.", bytes_read, src_addr);
 
                         let msg: Msg = serde_cbor::de::from_slice(&service_buffer[..bytes_read])
                             .expect("Failed to deserialize msg from service socket");
+                        // the client's request id, for the copies of its updates
+                        let request = msg
+                            .payload
+                            .as_ref()
+                            .map(|id| format!("{}:{}", src_addr, id));
+
+                        // waits for a majority, on its own not to hold up the rest
+                        let changes_state = matches!(
+                            msg.msg_type,
+                            Type::DirOfServJoin
+                                | Type::DirOfServLeave
+                                | Type::UpdateAccess(..)
+                                | Type::ViewGrant(..)
+                                | Type::ViewTokenRequest(..)
+                        );
+                        if changes_state {
+                            tokio::spawn(handle_state_change(
+                                msg.msg_type,
+                                src_addr,
+                                request,
+                                service_socket.clone(),
+                                election_socket.clone(),
+                                stats_service.clone(),
+                                log.clone(),
+                                dir_of_service.clone(),
+                                keyring.clone(),
+                            ));
+                            continue;
+                        }
 
                         match msg.msg_type {
                             Type::Fragment(frag) => {
@@ -556,44 +1877,62 @@ async fn main() {
 
                                     println!("{}", data.len());
 
-                                    // let default_image = match data.len() {
-                                    //     len if len > 9100000 => def6.clone(),
-                                    //     len if len > 3000000 => def5.clone(),
-                                    //     len if len > 2100000 => def4.clone(),
-                                    //     len if len > 670000 => def3.clone(),
-                                    //     len if len > 350000 => def2.clone(),
-                                    //     _ => def1.clone(),
-                                    // };
-
-                                    let default_image = match data.len() {
-                                        len if len > 9100000 => {
-                                            img_loader.load_image("default_images/def6.png")
-                                        }
-                                        len if len > 3000000 => {
-                                            img_loader.load_image("default_images/def5.png")
-                                        }
-                                        len if len > 2100000 => {
-                                            img_loader.load_image("default_images/def4.png")
+                                    // a cover is decoded on first use, off the executor
+                                    let selected = match cover_pool.clone() {
+                                        None => None,
+                                        Some(pool) => {
+                                            let secret_len = data.len();
+                                            let select = tokio::task::spawn_blocking(move || {
+                                                pool.blocking_lock().select(secret_len)
+                                            });
+                                            Some(select.await.unwrap_or(None))
                                         }
-                                        len if len > 670000 => {
-                                            img_loader.load_image("default_images/def3.png")
-                                        }
-                                        len if len > 350000 => {
-                                            img_loader.load_image("default_images/def2.png")
-                                        }
-                                        _ => img_loader.load_image("default_images/def1.png"),
                                     };
+                                    let covers = match selected {
+                                        None => Covers::Generated(rand::thread_rng().gen()),
+                                        Some(selected) => match selected {
+                                            Some(covers) => Covers::Pool(covers),
+                                            None => {
+                                                println!(
+                                                    "[{}] No covers available for {} bytes, refusing request",
+                                                    req_id,
+                                                    data.len()
+                                                );
+                                                channels_map.remove(&req_id);
+                                                refuse_client(
+                                                    &send_socket,
+                                                    src_addr,
+                                                    "no cover large enough",
+                                                )
+                                                .await;
+                                                continue;
+                                            }
+                                        },
+                                    };
+
+                                    // counted in the election priority until done
+                                    let in_flight =
+                                        InFlight::start(stats_service.clone(), data.len() as u64)
+                                            .await;
+                                    let slow_encryption =
+                                        stats_service.lock().await.faults.slow_encryption;
 
                                     tokio::spawn(async move {
+                                        let _in_flight = in_flight;
+                                        if let Some(delay) = slow_encryption {
+                                            println!("[{}] slowed down by {:?}", req_id, delay);
+                                            sleep(delay).await;
+                                        }
                                         handle_encryption(
                                             data,
                                             send_socket,
                                             src_addr,
                                             req_id.clone(),
                                             rx,
-                                            default_image.clone(),
+                                            covers,
+                                            pixel_order,
                                         )
-                                        .await
+                                        .await;
                                     });
                                 }
                             }
@@ -613,28 +1952,34 @@ async fn main() {
                                     .query_reply(service_socket.clone(), src_addr)
                                     .await;
                             }
-                            Type::DirOfServJoin => {
-                                dir_of_service.lock().await.client_join(src_addr).await;
-                            }
-                            Type::DirOfServLeave => {
-                                dir_of_service.lock().await.client_leave(src_addr).await;
-                            }
 
                             // Type::DirOfServQueryReply(d) => {
                             //     dir_of_service.lock().await.update(d).await
                             // }
-                            Type::UpdateAccess(img_id, action) => {
-                                dir_of_service
+                            Type::ClientDirOfServQueryPending => {
+                                let delivered = dir_of_service
                                     .lock()
                                     .await
-                                    .handle_access_update_req(img_id, action)
-                                    .await
+                                    .client_query_pending_reply(service_socket.clone(), src_addr)
+                                    .await;
+                                if let Some(delivered) = delivered {
+                                    submit_update(
+                                        delivered,
+                                        false,
+                                        None,
+                                        &stats_service,
+                                        &log,
+                                        &election_socket,
+                                        &dir_of_service,
+                                    )
+                                    .await;
+                                }
                             }
-                            Type::ClientDirOfServQueryPending => {
+                            Type::ViewGrantsQuery => {
                                 dir_of_service
                                     .lock()
                                     .await
-                                    .client_query_pending_reply(service_socket.clone(), src_addr)
+                                    .view_grants_reply(service_socket.clone(), src_addr)
                                     .await;
                             }
                             _ => {}
@@ -658,10 +2003,20 @@ async fn main() {
                         {
                             continue;
                         }
+                        if stats
+                            .lock()
+                            .await
+                            .faults
+                            .should_drop(SocketKind::Election, src_addr)
+                        {
+                            continue;
+                        }
                         let service_socket = Arc::clone(&service_socket2);
                         let election_socket = Arc::clone(&election_socket);
                         let stats_clone = Arc::clone(&stats_election);
                         let dir_of_service_clone = Arc::clone(&dir_of_service2);
+                        let log_clone = Arc::clone(&log_election);
+                        let keyring_clone = Arc::clone(&keyring_election);
                         tokio::spawn(async move {
                             handle_elec_request(
                                 &election_buffer[..bytes_read],
@@ -670,6 +2025,8 @@ async fn main() {
                                 election_socket,
                                 &stats_clone,
                                 dir_of_service_clone,
+                                log_clone,
+                                keyring_clone,
                             )
                             .await;
                         });
@@ -690,11 +2047,32 @@ impl ServerStats {
     fn new() -> ServerStats {
         ServerStats {
             requests_buffer: HashMap::new(),
+            election_ages: HashMap::new(),
+            election_metrics: ElectionMetrics::default(),
+            completed_requests: HashMap::new(),
+            lease_mode: false,
+            lease: None,
+            loads: HashMap::new(),
+            load: LoadSample::default(),
+            weights: PriorityWeights::default(),
             elections_initiated_by_me: HashSet::new(),
             elections_received_oks: HashSet::new(),
+            election_replies: HashMap::new(),
+            reply_notifiers: HashMap::new(),
+            quorum_probes: HashMap::new(),
             running_elections: HashMap::new(),
             peer_servers: Vec::new(),
+            left: HashSet::new(),
+            joining: false,
+            last_heartbeats: HashMap::new(),
+            suspected: HashSet::new(),
             own_ips: None,
+            mode: String::new(),
+            quorum: true,
+            gossip: false,
+            faults: Faults::default(),
+            fault_admin: None,
+            last_fault_order: 0,
             down: false,
         }
     }
@@ -702,4 +2080,147 @@ impl ServerStats {
     fn get_peer_servers(&self) -> Vec<(SocketAddr, SocketAddr, SocketAddr)> {
         self.peer_servers.clone()
     }
+
+    // Forgets what was heard so far, every peer gets a full timeout to send its
+    // next heartbeat. After we were down ourselves, our view of the peers is stale.
+    fn reset_failure_detector(&mut self) {
+        let now = Instant::now();
+        self.last_heartbeats = self.peer_servers.iter().map(|peer| (peer.1, now)).collect();
+        self.suspected.clear();
+    }
+
+    // of all the servers in servers.txt, ourselves included
+    fn majority(&self) -> usize {
+        let servers = self.peer_servers.len() + 1;
+        servers / 2 + 1
+    }
+
+    // whether we can see a majority of the servers, ourselves included
+    fn has_quorum(&self) -> bool {
+        if self.joining {
+            return false;
+        }
+        !self.quorum || self.live_peer_servers().len() + 1 >= self.majority()
+    }
+
+    // whether the server is new to us, one that left only comes back once taken
+    // out of `left`
+    fn add_peer(&mut self, peer: (SocketAddr, SocketAddr, SocketAddr)) -> bool {
+        if Some(peer) == self.own_ips
+            || self.left.contains(&peer.1)
+            || self.peer_servers.iter().any(|known| known.1 == peer.1)
+        {
+            return false;
+        }
+        self.peer_servers.push(peer);
+        self.last_heartbeats.insert(peer.1, Instant::now());
+        true
+    }
+
+    // whether the server was one of our peers
+    fn remove_peer(&mut self, election_addr: SocketAddr) -> bool {
+        self.left.insert(election_addr);
+        self.last_heartbeats.remove(&election_addr);
+        self.suspected.remove(&election_addr);
+        self.loads.remove(&election_addr);
+        let before = self.peer_servers.len();
+        self.peer_servers.retain(|peer| peer.1 != election_addr);
+        self.peer_servers.len() != before
+    }
+
+    // every member, ourselves included
+    fn members(&self) -> Vec<(SocketAddr, SocketAddr, SocketAddr)> {
+        let mut members = self.peer_servers.clone();
+        members.extend(self.own_ips);
+        members.sort();
+        members
+    }
+
+    // what clients know the servers by: service and election addresses
+    fn cloud_servers(&self) -> Vec<(SocketAddr, SocketAddr)> {
+        self.members()
+            .into_iter()
+            .map(|(ip_service, ip_elec, _)| (ip_service, ip_elec))
+            .collect()
+    }
+
+    // the peers not suspected dead, the only ones taking part in elections
+    fn live_peer_servers(&self) -> Vec<(SocketAddr, SocketAddr, SocketAddr)> {
+        self.peer_servers
+            .iter()
+            .filter(|peer| !self.suspected.contains(&peer.1))
+            .cloned()
+            .collect()
+    }
+
+    fn is_completed(&self, req_id: &str) -> bool {
+        match parse_req_id(req_id) {
+            Some((client, id)) => self
+                .completed_requests
+                .get(&client)
+                .is_some_and(|ids| ids.contains(id)),
+            None => false,
+        }
+    }
+
+    fn complete(&mut self, req_id: &str) {
+        if let Some((client, id)) = parse_req_id(req_id) {
+            self.completed_requests
+                .entry(client)
+                .or_default()
+                .insert(id);
+        }
+    }
+
+    fn touch_election(&mut self, req_id: &str) {
+        self.election_ages
+            .entry(String::from(req_id))
+            .or_insert(ElectionAge {
+                started: Instant::now(),
+                restarts: 0,
+            });
+    }
+
+    // election priority of this server, higher is better
+    fn priority(&self) -> f32 {
+        self.load.priority(&self.weights)
+    }
+
+    // the leader, while its lease lasts
+    fn lease_holder(&self) -> Option<SocketAddr> {
+        self.lease
+            .filter(|lease| lease.expires > Instant::now())
+            .map(|lease| lease.leader)
+    }
+
+    fn grant_lease(&mut self, leader: SocketAddr) {
+        self.lease = Some(Lease {
+            leader,
+            expires: Instant::now() + Duration::from_millis(LEASE_MILLIS),
+        });
+    }
+
+    // Service address of the server with the highest recently reported priority,
+    // ours included. It is charged a penalty so a burst of requests is spread out.
+    fn pick_server(&mut self) -> SocketAddr {
+        let own_ips = self.own_ips.unwrap();
+        let fresh = Duration::from_millis(LEASE_MILLIS);
+        let best = self
+            .loads
+            .iter()
+            .filter(|(_, (_, reported))| reported.elapsed() < fresh)
+            .max_by(|a, b| (a.1).0.total_cmp(&(b.1).0))
+            .map_or(own_ips.1, |(addr, _)| *addr);
+        if let Some((priority, _)) = self.loads.get_mut(&best) {
+            *priority -= ASSIGN_PENALTY;
+        }
+        if best == own_ips.1 {
+            own_ips.0
+        } else {
+            self.peer_servers
+                .iter()
+                .find(|peer| peer.1 == best)
+                .map_or(own_ips.0, |peer| peer.0)
+        }
+    }
 }
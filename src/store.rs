@@ -0,0 +1,125 @@
+use crate::client::{OwnShare, Request};
+use crate::commons::REQ_ID_LOG_FILEPATH;
+use crate::utils::{get_req_id_log, now_secs, write_atomic};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::{fs, net::SocketAddr, sync::Arc};
+use tokio::sync::Mutex;
+
+// (img_id, remaining views) of the images shared with us, by owner
+type ReceivedShares = HashMap<SocketAddr, Vec<(String, u32)>>;
+
+// What a client knows about who has access to what, saved next to the pictures
+// so it survives restarts.
+#[derive(Serialize, Deserialize, Default)]
+struct ClientState {
+    own_shared_imgs: HashMap<SocketAddr, Vec<OwnShare>>,
+    received_shared_imgs: ReceivedShares,
+    requests: HashMap<String, Request>,
+    #[serde(default)]
+    online_shares: HashSet<String>, // img_ids of our shares viewed in online views mode
+    #[serde(default)]
+    applied_updates: VecDeque<String>, // ids of the pending updates applied, oldest first
+}
+
+// pending update ids remembered, the cloud only hands over again recent ones
+const MAX_APPLIED_UPDATES: usize = 1024;
+
+// Shares the client's state maps and writes all of them out on every change.
+// The state is small, so the whole file is rewritten each time: to a temporary
+// file first and then renamed over the old one, so a crash leaves either the
+// old or the new state on disk, never a mix.
+#[derive(Clone)]
+pub struct StateStore {
+    path: String,
+    write_lock: Arc<Mutex<()>>,
+    pub own_shared_imgs: Arc<Mutex<HashMap<SocketAddr, Vec<OwnShare>>>>,
+    pub received_shared_imgs: Arc<Mutex<ReceivedShares>>,
+    pub requests: Arc<Mutex<HashMap<String, Request>>>,
+    pub online_shares: Arc<Mutex<HashSet<String>>>,
+    pub applied_updates: Arc<Mutex<VecDeque<String>>>,
+}
+
+impl StateStore {
+    pub fn load(path: &str) -> StateStore {
+        let state: ClientState = match fs::read(path) {
+            Ok(bytes) => serde_cbor::from_slice(&bytes).unwrap_or_else(|e| {
+                println!("Client state {} is corrupted, starting empty: {}", path, e);
+                ClientState::default()
+            }),
+            Err(_) => ClientState::default(),
+        };
+        StateStore {
+            path: String::from(path),
+            write_lock: Arc::new(Mutex::new(())),
+            own_shared_imgs: Arc::new(Mutex::new(state.own_shared_imgs)),
+            received_shared_imgs: Arc::new(Mutex::new(state.received_shared_imgs)),
+            requests: Arc::new(Mutex::new(state.requests)),
+            online_shares: Arc::new(Mutex::new(state.online_shares)),
+            applied_updates: Arc::new(Mutex::new(state.applied_updates)),
+        }
+    }
+
+    // must not be called while holding one of the state locks
+    pub async fn save(&self) {
+        let _write = self.write_lock.lock().await;
+        let state = ClientState {
+            own_shared_imgs: self.own_shared_imgs.lock().await.clone(),
+            received_shared_imgs: self.received_shared_imgs.lock().await.clone(),
+            requests: self.requests.lock().await.clone(),
+            online_shares: self.online_shares.lock().await.clone(),
+            applied_updates: self.applied_updates.lock().await.clone(),
+        };
+        let bytes = serde_cbor::to_vec(&state).unwrap();
+        if let Err(e) = write_atomic(&self.path, &bytes) {
+            println!("Failed to save client state {}: {}", self.path, e);
+        }
+    }
+
+    // Whether a pending update from the cloud is new to us, in which case it is
+    // remembered. The cloud hands an update over again when the news of its
+    // delivery is lost, and a view receipt must not count twice.
+    pub async fn first_application(&self, id: &str) -> bool {
+        let mut applied = self.applied_updates.lock().await;
+        if applied.iter().any(|other| other == id) {
+            return false;
+        }
+        if applied.len() == MAX_APPLIED_UPDATES {
+            applied.pop_front();
+        }
+        applied.push_back(String::from(id));
+        true
+    }
+}
+
+// Ids of our requests to the cloud. Servers key their election state by
+// "{addr}:{id}" and refuse ids they have already served, so an id must never be
+// handed out twice, crashes included: the counter is saved before each id is
+// used, rather than on quit. Without a saved counter (first run, or the file was
+// lost) ids start from the current time, past any id handed out before, and
+// never below the counter clients used to share in req_id_log.txt.
+pub struct RequestIds {
+    path: String,
+    next: Mutex<u32>,
+}
+
+impl RequestIds {
+    pub fn load(path: &str) -> RequestIds {
+        RequestIds {
+            path: String::from(path),
+            next: Mutex::new(get_req_id_log(path).unwrap_or_else(|| {
+                let legacy = get_req_id_log(REQ_ID_LOG_FILEPATH).unwrap_or(0);
+                legacy.max(now_secs() as u32)
+            })),
+        }
+    }
+
+    pub async fn next(&self) -> u32 {
+        let mut next = self.next.lock().await;
+        let id = *next;
+        *next += 1;
+        write_atomic(&self.path, next.to_string().as_bytes())
+            .expect("Failed to save the next request id");
+        id
+    }
+}
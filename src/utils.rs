@@ -1,7 +1,11 @@
 use crate::commons::{ELECTION_PORT, SERVICE_PORT, SERVICE_SENDBACK_PORT};
 use crate::commons::{ENCRYPTED_PICS_PATH, HIGH_RES_PICS_PATH, LOW_RES_PICS_PATH, PICS_ROOT_PATH};
-use std::{fs, net::SocketAddr};
+use crate::encryption::PixelOrder;
 use log::error;
+use rand::RngCore;
+use std::io::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::{fs, net::SocketAddr};
 
 pub async fn get_peer_servers(
     filepath: &str,
@@ -49,11 +53,55 @@ pub async fn get_ips(ip: &str, mode: &str) -> (SocketAddr, SocketAddr, SocketAdd
     (ip_service, ip_elec, ip_send)
 }
 
-pub fn get_req_id_log(filepath: &str) -> u32 {
-    match fs::read_to_string(filepath) {
-        Ok(contents) => contents.parse::<u32>().unwrap_or(1),
-        Err(_) => 0, // Default value in case of an error or missing file
+// The next request id saved in filepath, None if there is no such file. Any other
+// failure stops the client: guessing would reuse ids the servers already served.
+pub fn get_req_id_log(filepath: &str) -> Option<u32> {
+    let contents = match fs::read_to_string(filepath) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return None,
+        Err(e) => {
+            error!("Cannot read the request ids in {}: {}", filepath, e);
+            std::process::exit(1);
+        }
+    };
+    match contents.trim().parse::<u32>() {
+        Ok(id) => Some(id),
+        Err(_) => {
+            error!("Request ids in {} are corrupted.", filepath);
+            std::process::exit(1);
+        }
+    }
+}
+
+// The embedding key is shared by the servers (which embed) and the clients (which extract).
+// It is stored as 64 hex characters; without a key file the raster order is used.
+pub fn get_pixel_order(filepath: &str) -> PixelOrder {
+    let contents = match fs::read_to_string(filepath) {
+        Ok(contents) => contents,
+        Err(_) => return PixelOrder::Raster,
+    };
+    match parse_hex_key(contents.as_str()) {
+        Some(key) => PixelOrder::Keyed(key),
+        None => {
+            error!("Embedding key in {} must be 64 hex characters.", filepath);
+            std::process::exit(1);
+        }
+    }
+}
+
+// A 32 byte key written as 64 hex characters
+pub fn parse_hex_key(hex: &str) -> Option<[u8; 32]> {
+    let hex = hex.trim();
+    // hex digits only: slicing must not split a character, and from_str_radix
+    // would take a sign
+    if hex.len() != 64 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    let mut key = [0u8; 32];
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).ok()?;
     }
+    Some(key)
 }
 
 pub fn get_cloud_servers(filepath: &str, mode: &str) -> Vec<(SocketAddr, SocketAddr)> {
@@ -126,3 +174,119 @@ pub fn file_exists(file_path: &str) -> bool {
         false
     }
 }
+
+// Overwrites a file with random bytes before removing it, so the old contents
+// are not left behind on disk.
+pub fn secure_delete(file_path: &str) -> std::io::Result<()> {
+    let len = fs::metadata(file_path)?.len() as usize;
+    let mut noise = vec![0u8; len];
+    rand::thread_rng().fill_bytes(&mut noise);
+    let mut file = fs::OpenOptions::new().write(true).open(file_path)?;
+    file.write_all(&noise)?;
+    file.sync_all()?;
+    drop(file);
+    fs::remove_file(file_path)
+}
+
+// Writes to a temporary file first and then renames it over the old one, so a
+// crash leaves either the old or the new contents on disk, never a mix.
+pub fn write_atomic(path: &str, bytes: &[u8]) -> std::io::Result<()> {
+    let tmp_path = format!("{}.tmp", path);
+    let mut file = fs::File::create(&tmp_path)?;
+    file.write_all(bytes)?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)
+}
+
+pub fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+// Durations are given as a number with an optional unit: s, m, h or d (e.g. 90m, 7d)
+pub fn parse_duration(input: &str) -> Option<u64> {
+    let input = input.trim();
+    let (number, unit) = match input.char_indices().last()? {
+        (i, c) if c.is_ascii_alphabetic() => (&input[..i], c),
+        _ => (input, 's'),
+    };
+    let secs = match unit {
+        's' => 1,
+        'm' => 60,
+        'h' => 60 * 60,
+        'd' => 24 * 60 * 60,
+        _ => return None,
+    };
+    number.trim().parse::<u64>().ok()?.checked_mul(secs)
+}
+
+// Times are given (in UTC) as YYYY-MM-DD, YYYY-MM-DD HH:MM or as unix time in seconds
+pub fn parse_time(input: &str) -> Option<u64> {
+    let input = input.trim();
+    if let Ok(secs) = input.parse::<u64>() {
+        return Some(secs);
+    }
+    let (date, time) = input.split_once(' ').unwrap_or((input, "00:00"));
+    let date: Vec<&str> = date.split('-').collect();
+    let time: Vec<&str> = time.trim().split(':').collect();
+    if date.len() != 3 || time.len() != 2 {
+        return None;
+    }
+    let (year, month, day) = (
+        date[0].parse::<i64>().ok()?,
+        date[1].parse::<u32>().ok()?,
+        date[2].parse::<u32>().ok()?,
+    );
+    let (hour, minute) = (time[0].parse::<u64>().ok()?, time[1].parse::<u64>().ok()?);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || minute > 59 {
+        return None;
+    }
+    // a day past the end of its month (2023-02-29, 2024-04-31) lands in the next
+    // month, so only dates that convert back to themselves exist
+    let days = days_from_civil(year, month, day);
+    if civil_from_days(days) != (year, month, day) {
+        return None;
+    }
+    let days = u64::try_from(days).ok()?;
+    Some(days * 24 * 60 * 60 + hour * 60 * 60 + minute * 60)
+}
+
+pub fn format_time(secs: u64) -> String {
+    let (year, month, day) = civil_from_days((secs / (24 * 60 * 60)) as i64);
+    let secs_of_day = secs % (24 * 60 * 60);
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02} UTC",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        (secs_of_day % 3600) / 60
+    )
+}
+
+// days since 1970-01-01 of a proleptic Gregorian date (H. Hinnant's algorithm)
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month = month as i64;
+    let day_of_year =
+        (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
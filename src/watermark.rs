@@ -0,0 +1,223 @@
+use image::{ImageBuffer, Rgba};
+use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+
+// Marks a decoded image with who viewed it and when, so a leaked screenshot can
+// be traced back to the viewer. Visible marks are a strip of black and white
+// cells along the bottom edge, invisible ones live in the blue channel's lowest
+// bit, repeated over the whole image.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum WatermarkMode {
+    Visible,
+    Invisible,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Watermark {
+    pub viewer_key: [u8; 32],
+    pub viewer: SocketAddr,
+    pub timestamp: u64, // unix time (secs)
+}
+
+const MAGIC: &[u8; 4] = b"WMK2";
+// magic, viewer key, address, timestamp and a checksum, so a mark read at the
+// wrong scale is not mistaken for one
+const WATERMARK_LEN: usize = 4 + 32 + 18 + 8 + 4;
+const WATERMARK_BITS: usize = 8 * WATERMARK_LEN;
+// side of one cell of the visible strip, in pixels
+const CELL: u32 = 8;
+
+impl Watermark {
+    fn to_bytes(&self) -> Vec<u8> {
+        let ip = match self.viewer.ip() {
+            IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+            IpAddr::V6(ip) => ip,
+        };
+        let bytes = [
+            &MAGIC[..],
+            &self.viewer_key,
+            &ip.octets(),
+            &self.viewer.port().to_be_bytes(),
+            &self.timestamp.to_be_bytes(),
+        ]
+        .concat();
+        [bytes.clone(), checksum(&bytes)].concat()
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<Watermark> {
+        if bytes.len() != WATERMARK_LEN
+            || &bytes[..4] != MAGIC
+            || bytes[62..] != checksum(&bytes[..62])[..]
+        {
+            return None;
+        }
+        let ip = Ipv6Addr::from(<[u8; 16]>::try_from(&bytes[36..52]).unwrap());
+        let ip = match ip.to_ipv4_mapped() {
+            Some(ip) => IpAddr::V4(ip),
+            None => IpAddr::V6(ip),
+        };
+        let port = u16::from_be_bytes(bytes[52..54].try_into().unwrap());
+        Some(Watermark {
+            viewer_key: bytes[4..36].try_into().unwrap(),
+            viewer: SocketAddr::new(ip, port),
+            timestamp: u64::from_be_bytes(bytes[54..62].try_into().unwrap()),
+        })
+    }
+}
+
+fn checksum(bytes: &[u8]) -> Vec<u8> {
+    Sha256::digest(bytes)[..4].to_vec()
+}
+
+fn bits(bytes: &[u8]) -> Vec<bool> {
+    bytes
+        .iter()
+        .flat_map(|byte| (0..8).rev().map(move |i| (byte >> i) & 1 == 1))
+        .collect()
+}
+
+fn from_bits(bits: &[bool]) -> Vec<u8> {
+    bits.chunks(8)
+        .map(|byte| byte.iter().fold(0, |acc, bit| (acc << 1) | *bit as u8))
+        .collect()
+}
+
+// Stamps the watermark onto `img`. A visible mark that does not fit the image
+// falls back to an invisible one.
+pub fn stamp(img: &mut ImageBuffer<Rgba<u8>, Vec<u8>>, mark: &Watermark, mode: WatermarkMode) {
+    let bits = bits(&mark.to_bytes());
+    if mode == WatermarkMode::Visible && stamp_visible(img, &bits) {
+        return;
+    }
+    for (i, pixel) in img.pixels_mut().enumerate() {
+        pixel[2] = (pixel[2] & !1) | bits[i % WATERMARK_BITS] as u8;
+    }
+}
+
+// (columns, rows) of the visible strip, None if it does not fit
+fn strip_layout(width: u32, height: u32) -> Option<(u32, u32)> {
+    let cols = width / CELL;
+    if cols == 0 {
+        return None;
+    }
+    let rows = (WATERMARK_BITS as u32).div_ceil(cols);
+    if rows * CELL > height {
+        return None;
+    }
+    Some((cols, rows))
+}
+
+fn stamp_visible(img: &mut ImageBuffer<Rgba<u8>, Vec<u8>>, bits: &[bool]) -> bool {
+    let (cols, rows) = match strip_layout(img.width(), img.height()) {
+        Some(layout) => layout,
+        None => return false,
+    };
+    let top = img.height() - rows * CELL;
+    for (i, bit) in bits.iter().enumerate() {
+        let (cx, cy) = (i as u32 % cols, i as u32 / cols);
+        for y in 0..CELL {
+            for x in 0..CELL {
+                let pixel = img.get_pixel_mut(cx * CELL + x, top + cy * CELL + y);
+                // half way to white or black, the picture still shows through
+                for c in 0..3 {
+                    pixel[c] = if *bit {
+                        ((pixel[c] as u16 + 256) / 2) as u8
+                    } else {
+                        pixel[c] / 2
+                    };
+                }
+            }
+        }
+    }
+    true
+}
+
+// Reads a watermark back out of a viewed image, or a lossless screenshot of it.
+// The viewer shows images scaled up, so the mark is looked for at the sizes the
+// image may have had before scaling: the visible strip at any size (its cells
+// are sampled in the middle, which survives smoothing), the invisible bits only
+// under whole number scale factors, as the lowest bit does not survive smoothing.
+pub fn extract(img: &ImageBuffer<Rgba<u8>, Vec<u8>>) -> Option<(Watermark, WatermarkMode)> {
+    if let Some(mark) = extract_visible(img) {
+        return Some((mark, WatermarkMode::Visible));
+    }
+    extract_invisible(img).map(|mark| (mark, WatermarkMode::Invisible))
+}
+
+// how much smaller or larger than the screenshot the stamped image may have been
+const MAX_UPSCALE: u32 = 8;
+const MAX_DOWNSCALE: u32 = 4;
+
+// The pixel of `img` showing pixel (x, y) of the image as stamped at width x height
+fn sample(img: &ImageBuffer<Rgba<u8>, Vec<u8>>, size: (u32, u32), x: u32, y: u32) -> &Rgba<u8> {
+    let scale_x = img.width() as f64 / size.0 as f64;
+    let scale_y = img.height() as f64 / size.1 as f64;
+    let x = ((x as f64 + 0.5) * scale_x) as u32;
+    let y = ((y as f64 + 0.5) * scale_y) as u32;
+    img.get_pixel(x.min(img.width() - 1), y.min(img.height() - 1))
+}
+
+// the stamped size for a stamped width, keeping the screenshot's aspect ratio
+fn stamped_size(img: &ImageBuffer<Rgba<u8>, Vec<u8>>, width: u32) -> (u32, u32) {
+    let height = (img.height() as u64 * width as u64 + img.width() as u64 / 2) / img.width() as u64;
+    (width, height as u32)
+}
+
+fn extract_visible(img: &ImageBuffer<Rgba<u8>, Vec<u8>>) -> Option<Watermark> {
+    if img.width() == 0 {
+        return None;
+    }
+    // whole number scale factors first, they are what the viewer uses
+    let widths = (1..=MAX_UPSCALE)
+        .map(|k| img.width() / k)
+        .chain(img.width() / MAX_UPSCALE..=img.width() * MAX_DOWNSCALE);
+    widths
+        .filter(|width| *width > 0)
+        .find_map(|width| extract_visible_at(img, stamped_size(img, width)))
+}
+
+fn extract_visible_at(img: &ImageBuffer<Rgba<u8>, Vec<u8>>, size: (u32, u32)) -> Option<Watermark> {
+    let (cols, rows) = strip_layout(size.0, size.1)?;
+    let top = size.1 - rows * CELL;
+    let bits: Vec<bool> = (0..WATERMARK_BITS as u32)
+        .map(|i| {
+            let pixel = sample(
+                img,
+                size,
+                (i % cols) * CELL + CELL / 2,
+                top + (i / cols) * CELL + CELL / 2,
+            );
+            let luma = (pixel[0] as u16 + pixel[1] as u16 + pixel[2] as u16) / 3;
+            luma >= 128
+        })
+        .collect();
+    Watermark::from_bytes(&from_bits(&bits))
+}
+
+fn extract_invisible(img: &ImageBuffer<Rgba<u8>, Vec<u8>>) -> Option<Watermark> {
+    (1..=MAX_UPSCALE)
+        .map(|k| (img.width() / k, img.height() / k))
+        .filter(|(width, height)| width * height >= WATERMARK_BITS as u32)
+        .find_map(|size| extract_invisible_at(img, size))
+}
+
+fn extract_invisible_at(
+    img: &ImageBuffer<Rgba<u8>, Vec<u8>>,
+    size: (u32, u32),
+) -> Option<Watermark> {
+    // majority vote over every copy, so locally edited areas do not matter
+    let mut votes = vec![0i64; WATERMARK_BITS];
+    for y in 0..size.1 {
+        for x in 0..size.0 {
+            let i = (y * size.0 + x) as usize;
+            votes[i % WATERMARK_BITS] += if sample(img, size, x, y)[2] & 1 == 1 {
+                1
+            } else {
+                -1
+            };
+        }
+    }
+    let bits: Vec<bool> = votes.iter().map(|vote| *vote > 0).collect();
+    Watermark::from_bytes(&from_bits(&bits))
+}
@@ -0,0 +1,228 @@
+// Gossip mode: directory states merged by anti-entropy converge, and nothing
+// only one server had seen is lost when it recovers. Run with `cargo test`.
+#![allow(dead_code, unused_imports)]
+
+#[path = "../src/commons.rs"]
+mod commons;
+#[path = "../src/dir_of_service.rs"]
+mod dir_of_service;
+#[path = "../src/fragment.rs"]
+mod fragment;
+
+use commons::{Action, Command};
+use dir_of_service::{split_state, ServerDirOfService};
+use std::net::SocketAddr;
+
+fn addr(port: u16) -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], port))
+}
+
+// the id of a client's update, as the servers make it up from its request
+fn request(id: u32) -> String {
+    format!("127.0.0.1:4000:{}", id)
+}
+
+// a client's cloud socket, the directory keeps the next port
+fn joined(dir: &commons::DirState, client: SocketAddr) -> Option<bool> {
+    let client = SocketAddr::new(client.ip(), client.port() + 1);
+    dir.entries.get(&client).map(|entry| entry.value)
+}
+
+#[tokio::test]
+async fn join_seen_only_by_failed_server_is_kept() {
+    let mut servers: Vec<ServerDirOfService> = (0..3)
+        .map(|i| ServerDirOfService::new(addr(8081 + 10 * i)))
+        .collect();
+    let (alice, bob) = (addr(4000), addr(5000));
+
+    // only server 0 gets alice's join, then it fails
+    servers[0].apply(Command::Join(alice), None).await.unwrap();
+    // meanwhile bob joins everywhere else
+    for server in &mut servers[1..] {
+        server.apply(Command::Join(bob), None).await.unwrap();
+    }
+
+    // on recovery server 0 merges every peer's reply, in whatever order
+    for i in [2, 1] {
+        let state = servers[i].state().await;
+        servers[0].merge(addr(8081 + 10 * i as u16), state).await;
+    }
+    let recovered = servers[0].state().await;
+    assert_eq!(joined(&recovered, alice), Some(true));
+    assert_eq!(joined(&recovered, bob), Some(true));
+
+    // anti-entropy rounds spread the join to the others
+    for (from, to) in [(0, 1), (1, 2)] {
+        let state = servers[from].state().await;
+        servers[to]
+            .merge(addr(8081 + 10 * from as u16), state)
+            .await;
+    }
+    for server in &servers {
+        assert_eq!(server.state().await, recovered);
+    }
+}
+
+#[tokio::test]
+async fn concurrent_leave_and_join_converge() {
+    let mut a = ServerDirOfService::new(addr(8081));
+    let mut b = ServerDirOfService::new(addr(8091));
+    let alice = addr(4000);
+    a.apply(Command::Join(alice), None).await.unwrap();
+    b.merge(addr(8081), a.state().await).await;
+
+    // alice leaves and joins again, each message reaching one server only
+    a.apply(Command::Leave(alice), None).await.unwrap();
+    b.apply(Command::Leave(alice), None).await.unwrap();
+    b.apply(Command::Join(alice), None).await.unwrap();
+
+    let (state_a, state_b) = (a.state().await, b.state().await);
+    a.merge(addr(8091), state_b).await;
+    b.merge(addr(8081), state_a).await;
+    assert_eq!(a.state().await, b.state().await);
+    assert_eq!(joined(&a.state().await, alice), Some(true));
+}
+
+#[tokio::test]
+async fn broadcast_update_is_kept_once_and_delivery_sticks() {
+    let mut a = ServerDirOfService::new(addr(8081));
+    let mut b = ServerDirOfService::new(addr(8091));
+    let (owner, recipient) = (addr(4001), addr(5001));
+    let img_id = format!("{}&{}&cat.png", owner, recipient);
+
+    // the owner's update reaches both servers, a later one only a
+    for server in [&mut a, &mut b] {
+        let update = Command::AccessUpdate(img_id.clone(), Action::Increment(2));
+        server.apply(update, Some(request(1))).await.unwrap();
+    }
+    let update = Command::AccessUpdate(img_id.clone(), Action::Revoke);
+    a.apply(update, Some(request(2))).await.unwrap();
+
+    let (state_a, state_b) = (a.state().await, b.state().await);
+    a.merge(addr(8091), state_b).await;
+    b.merge(addr(8081), state_a).await;
+    let pending = |dir: &commons::DirState| {
+        dir.pending_updates[&recipient][&format!("{}&cat.png", owner)]
+            .value
+            .pending()
+    };
+    assert_eq!(
+        pending(&b.state().await),
+        vec![Action::Increment(2), Action::Revoke]
+    );
+
+    // handed over by b, then a's stale copy comes back by anti-entropy
+    let stale = a.state().await;
+    let delivered = [(format!("{}&cat.png", owner), vec![request(1), request(2)])];
+    let delivered = Command::PendingDelivered(recipient, delivered.into_iter().collect());
+    b.apply(delivered, None).await.unwrap();
+    b.merge(addr(8081), stale).await;
+    assert!(pending(&b.state().await).is_empty());
+    a.merge(addr(8091), b.state().await).await;
+    assert!(pending(&a.state().await).is_empty());
+}
+
+#[tokio::test]
+async fn malformed_image_id_changes_nothing() {
+    let mut dir = ServerDirOfService::new(addr(8081));
+    let before = dir.state().await;
+    for img_id in [
+        "cat.png",
+        "127.0.0.1:4001&cat.png",
+        "x&127.0.0.1:5001&cat.png",
+    ] {
+        let update = Command::AccessUpdate(img_id.to_string(), Action::Revoke);
+        assert!(dir.apply(update, None).await.is_err());
+    }
+    assert_eq!(dir.state().await, before);
+}
+
+#[tokio::test]
+async fn equal_updates_seen_by_different_servers_are_both_kept() {
+    let mut a = ServerDirOfService::new(addr(8081));
+    let mut b = ServerDirOfService::new(addr(8091));
+    let (owner, recipient) = (addr(4001), addr(5001));
+    let img_id = format!("{}&{}&cat.png", owner, recipient);
+
+    // two requests for one more view each, one reaching a twice, the other b
+    for id in [1, 1] {
+        let update = Command::AccessUpdate(img_id.clone(), Action::Increment(1));
+        a.apply(update, Some(request(id))).await.unwrap();
+    }
+    let update = Command::AccessUpdate(img_id.clone(), Action::Increment(1));
+    b.apply(update, Some(request(2))).await.unwrap();
+
+    let (state_a, state_b) = (a.state().await, b.state().await);
+    a.merge(addr(8091), state_b).await;
+    b.merge(addr(8081), state_a).await;
+    let state = a.state().await;
+    assert_eq!(state, b.state().await);
+    let pending = &state.pending_updates[&recipient][&format!("{}&cat.png", owner)];
+    assert_eq!(
+        pending.value.pending(),
+        vec![Action::Increment(1), Action::Increment(1)]
+    );
+}
+
+#[tokio::test]
+async fn state_parts_fit_and_merge_into_the_whole() {
+    let mut full = ServerDirOfService::new(addr(8081));
+    for i in 0..200 {
+        full.apply(Command::Join(addr(6000 + 2 * i)), None)
+            .await
+            .unwrap();
+        let img_id = format!("{}&{}&cat.png", addr(4001), addr(6001 + 2 * i));
+        let update = Command::AccessUpdate(img_id, Action::Increment(1));
+        full.apply(update, None).await.unwrap();
+    }
+
+    let parts = split_state(full.state().await, 1024);
+    assert!(parts.len() > 1);
+    let mut rebuilt = ServerDirOfService::new(addr(8091));
+    for part in parts {
+        assert!(serde_cbor::to_vec(&part).unwrap().len() <= 1024);
+        rebuilt.merge(addr(8081), part).await;
+    }
+    assert_eq!(rebuilt.state().await, full.state().await);
+}
+
+#[tokio::test]
+async fn delivered_ids_go_once_every_replica_saw_them() {
+    let (addr_a, addr_b) = (addr(8081), addr(8091));
+    let mut a = ServerDirOfService::new(addr_a);
+    let mut b = ServerDirOfService::new(addr_b);
+    let (owner, recipient) = (addr(4001), addr(5001));
+    let img_id = format!("{}&{}&cat.png", owner, recipient);
+    let update = Command::AccessUpdate(img_id, Action::Revoke);
+    a.apply(update, Some(request(1))).await.unwrap();
+    b.merge(addr_a, a.state().await).await;
+
+    let delivered = [(format!("{}&cat.png", owner), vec![request(1)])];
+    let delivered = Command::PendingDelivered(recipient, delivered.into_iter().collect());
+    a.apply(delivered, None).await.unwrap();
+    let tombstones = |state: &commons::DirState| {
+        state.pending_updates[&recipient][&format!("{}&cat.png", owner)]
+            .value
+            .delivered
+            .len()
+    };
+
+    // b has not seen the delivery yet
+    a.collect_delivered(&[addr_b]).await;
+    assert_eq!(tombstones(&a.state().await), 1);
+
+    // once it has, and told a so, the id can go
+    b.merge(addr_a, a.state().await).await;
+    a.merge(addr_b, b.state().await).await;
+    a.collect_delivered(&[addr_b]).await;
+    assert_eq!(tombstones(&a.state().await), 0);
+
+    // b's copy of the id does not bring the update back
+    a.merge(addr_b, b.state().await).await;
+    b.merge(addr_a, a.state().await).await;
+    for dir in [&a, &b] {
+        let state = dir.state().await;
+        let pending = &state.pending_updates[&recipient][&format!("{}&cat.png", owner)];
+        assert!(pending.value.pending().is_empty());
+    }
+}
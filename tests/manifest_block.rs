@@ -0,0 +1,58 @@
+// The access manifest lives in pixels reserved at the end of every cover, so
+// writing it never touches the payload, whatever the pixel order and even when
+// the payload fills the cover. Run with `cargo test`.
+#![allow(dead_code, unused_imports)]
+
+#[path = "../src/commons.rs"]
+mod commons;
+#[path = "../src/cover_gen.rs"]
+mod cover_gen;
+#[path = "../src/encryption.rs"]
+mod encryption;
+#[path = "../src/fragment.rs"]
+mod fragment;
+
+use encryption::{
+    embed, extract, payload_capacity, read_manifest_block, write_manifest_block, PixelOrder,
+    MANIFEST_LEN, PART_HEADER_LEN,
+};
+use image::{ImageBuffer, Rgba};
+
+// a single part header followed by `secret`, as encode_img lays it out
+fn stream(secret: &[u8]) -> Vec<u8> {
+    [0u32, 1, secret.len() as u32]
+        .iter()
+        .flat_map(|n| n.to_be_bytes())
+        .chain(secret.iter().copied())
+        .collect()
+}
+
+fn round_trip(mut img: ImageBuffer<Rgba<u8>, Vec<u8>>, secret: &[u8], order: PixelOrder) {
+    let manifest = vec![0xab; MANIFEST_LEN - 4];
+    embed(&mut img, &stream(secret), order);
+    assert!(write_manifest_block(&mut img, &manifest));
+
+    let (header, extracted) = extract(&img, order).expect("header survives the manifest");
+    assert_eq!(header.len as usize, secret.len());
+    assert_eq!(extracted, secret);
+    assert_eq!(read_manifest_block(&img), Some(manifest));
+}
+
+#[test]
+fn manifest_does_not_overwrite_full_cover() {
+    let img = ImageBuffer::from_pixel(64, 48, Rgba([10, 20, 30, 40]));
+    let len = payload_capacity(64, 48) - PART_HEADER_LEN;
+    let secret: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+    round_trip(img.clone(), &secret, PixelOrder::Raster);
+    round_trip(img, &secret, PixelOrder::Keyed([7; 32]));
+}
+
+#[test]
+fn manifest_does_not_overwrite_generated_cover() {
+    let secret: Vec<u8> = (0..5000).map(|i| (i % 13) as u8).collect();
+    let capacity = secret.len() + PART_HEADER_LEN + MANIFEST_LEN;
+    for order in [PixelOrder::Raster, PixelOrder::Keyed([3; 32])] {
+        let cover = cover_gen::generate(42, capacity).into_rgba8();
+        round_trip(cover, &secret, order);
+    }
+}
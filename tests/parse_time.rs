@@ -0,0 +1,44 @@
+// Expiry dates typed by an owner are only accepted when the day exists in its
+// month, leap years included. Run with `cargo test`.
+#![allow(dead_code, unused_imports, unused_variables)]
+
+#[path = "../src/commons.rs"]
+mod commons;
+#[path = "../src/cover_gen.rs"]
+mod cover_gen;
+#[path = "../src/encryption.rs"]
+mod encryption;
+#[path = "../src/fragment.rs"]
+mod fragment;
+#[path = "../src/utils.rs"]
+mod utils;
+
+use utils::{format_time, parse_time};
+
+#[test]
+fn accepts_real_dates() {
+    for date in [
+        "2024-02-29",
+        "2000-02-29",
+        "2023-01-31",
+        "2023-04-30",
+        "1970-01-01",
+    ] {
+        let secs = parse_time(date).unwrap_or_else(|| panic!("{} rejected", date));
+        assert_eq!(format_time(secs), format!("{} 00:00 UTC", date));
+    }
+    assert_eq!(parse_time("2024-03-01 12:30"), Some(1_709_296_200));
+}
+
+#[test]
+fn rejects_days_past_the_end_of_the_month() {
+    for date in [
+        "2023-02-29",
+        "1900-02-29",
+        "2024-02-30",
+        "2023-04-31",
+        "2023-06-31",
+    ] {
+        assert_eq!(parse_time(date), None, "{} accepted", date);
+    }
+}
@@ -0,0 +1,113 @@
+// The replicated log: a follower's commit index never goes back when appends
+// arrive twice or out of order, client updates that come while no leader is
+// known are passed on once there is one, and the servers are those of the
+// log's config entries. Run with `cargo test`.
+#![allow(dead_code, unused_imports, unused_variables)]
+
+#[path = "../src/commons.rs"]
+mod commons;
+#[path = "../src/cover_gen.rs"]
+mod cover_gen;
+#[path = "../src/encryption.rs"]
+mod encryption;
+#[path = "../src/fragment.rs"]
+mod fragment;
+#[path = "../src/replog.rs"]
+mod replog;
+#[path = "../src/utils.rs"]
+mod utils;
+
+use commons::{Command, LogEntry, Type};
+use replog::ReplicatedLog;
+use std::net::SocketAddr;
+
+fn addr(port: u16) -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], port))
+}
+
+fn entry(port: u16) -> LogEntry {
+    LogEntry {
+        term: 1,
+        command: Command::Join(addr(port)),
+        request: None,
+    }
+}
+
+fn follower(name: &str, leader: SocketAddr) -> (ReplicatedLog, std::path::PathBuf) {
+    let path = std::env::temp_dir().join(format!("replog-{}-{}.cbor", name, std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let log = ReplicatedLog::load(path.to_str().unwrap(), addr(8091), vec![leader]);
+    (log, path)
+}
+
+#[test]
+fn commit_index_survives_a_stale_append() {
+    let leader = addr(8081);
+    let (mut log, path) = follower("stale", leader);
+
+    let entries = vec![entry(4001), entry(4002), entry(4003)];
+    log.handle(leader, Type::LogAppend(1, 0, 0, entries, 2));
+    assert_eq!(log.commit_index(), 2);
+    assert_eq!(log.take_committed().len(), 2);
+
+    // the first append again, delivered late once the leader committed more:
+    // it covers a single entry, which says nothing about the others
+    log.handle(leader, Type::LogAppend(1, 0, 0, vec![entry(4001)], 3));
+    assert_eq!(log.commit_index(), 2);
+    assert!(log.take_committed().is_empty());
+
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn update_without_leader_is_forwarded_later() {
+    let leader = addr(8081);
+    let (mut log, path) = follower("buffer", leader);
+
+    let join = Command::Join(addr(4001));
+    let request = Some(String::from("127.0.0.1:4000:7"));
+    assert!(log.propose_broadcast(request.clone(), join).is_empty());
+
+    // the first append makes the leader known
+    let outbox = log.handle(leader, Type::LogAppend(1, 0, 0, Vec::new(), 0));
+    let forwarded: Vec<_> = outbox
+        .iter()
+        .filter_map(|(to, msg_type)| match msg_type {
+            Type::LogForward(request, Command::Join(client)) => {
+                Some((*to, request.clone(), *client))
+            }
+            _ => None,
+        })
+        .collect();
+    assert_eq!(forwarded, vec![(leader, request, addr(4001))]);
+
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn config_follows_the_log_entries() {
+    let leader = addr(8081);
+    let (mut log, path) = follower("config", leader);
+    let joining = addr(8101);
+    assert_eq!(log.config(), vec![leader, addr(8091)]);
+
+    // counts as soon as it is in the log, committed or not
+    let add = LogEntry {
+        term: 1,
+        command: Command::AddServer(addr(8100), joining, addr(8102)),
+        request: None,
+    };
+    log.handle(leader, Type::LogAppend(1, 0, 0, vec![add], 0));
+    assert!(log.config().contains(&joining));
+
+    // a new leader never had it, the entry goes and so does the server
+    let noop = LogEntry {
+        term: 2,
+        command: Command::Noop,
+        request: None,
+    };
+    log.handle(leader, Type::LogAppend(2, 0, 0, vec![noop], 0));
+    assert_eq!(log.config(), vec![leader, addr(8091)]);
+
+    let _ = std::fs::remove_file(&path);
+}
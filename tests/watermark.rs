@@ -0,0 +1,60 @@
+// A watermark stamped onto a viewed image is still found once the image is shown
+// scaled, as the viewer window does. Run with `cargo test`.
+#![allow(dead_code, unused_imports)]
+
+#[path = "../src/watermark.rs"]
+mod watermark;
+
+use image::imageops::{self, FilterType};
+use image::{ImageBuffer, Rgba};
+use watermark::{Watermark, WatermarkMode};
+
+fn picture(width: u32, height: u32) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
+    ImageBuffer::from_fn(width, height, |x, y| {
+        Rgba([
+            (x * 7 % 256) as u8,
+            (y * 5 % 256) as u8,
+            ((x + y) % 256) as u8,
+            255,
+        ])
+    })
+}
+
+fn mark() -> Watermark {
+    Watermark {
+        viewer_key: [9; 32],
+        viewer: "127.0.0.1:4001".parse().unwrap(),
+        timestamp: 1_700_000_000,
+    }
+}
+
+fn round_trip(mode: WatermarkMode, width: u32, height: u32, filter: FilterType) {
+    let mut img = picture(320, 240);
+    watermark::stamp(&mut img, &mark(), mode);
+    let shown = imageops::resize(&img, width, height, filter);
+    assert_eq!(
+        watermark::extract(&shown),
+        Some((mark(), mode)),
+        "{:?} mark at {}x{} ({:?})",
+        mode,
+        width,
+        height,
+        filter
+    );
+}
+
+#[test]
+fn visible_mark_survives_scaling() {
+    round_trip(WatermarkMode::Visible, 320, 240, FilterType::Nearest);
+    round_trip(WatermarkMode::Visible, 640, 480, FilterType::Nearest);
+    round_trip(WatermarkMode::Visible, 640, 480, FilterType::Triangle);
+    round_trip(WatermarkMode::Visible, 480, 360, FilterType::Triangle);
+    round_trip(WatermarkMode::Visible, 240, 180, FilterType::Triangle);
+}
+
+#[test]
+fn invisible_mark_survives_whole_number_upscaling() {
+    round_trip(WatermarkMode::Invisible, 320, 240, FilterType::Nearest);
+    round_trip(WatermarkMode::Invisible, 640, 480, FilterType::Nearest);
+    round_trip(WatermarkMode::Invisible, 1280, 960, FilterType::Nearest);
+}